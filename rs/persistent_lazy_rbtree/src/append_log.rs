@@ -0,0 +1,92 @@
+use crate::{LazyParam, PersistentLazyRBTree};
+use std::mem;
+
+/// An append-only sequence that buffers pushed elements and builds them into
+/// a balanced subtree only when a snapshot is taken, so each push is O(1)
+/// instead of a descent along the right spine.
+pub struct AppendLog<T: LazyParam> {
+    tree: PersistentLazyRBTree<T>,
+    tail: Vec<T::Element>,
+}
+impl<T: LazyParam> AppendLog<T> {
+    pub fn new() -> Self {
+        Self {
+            tree: PersistentLazyRBTree::new(),
+            tail: vec![],
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.tree.len() + self.tail.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty() && self.tail.is_empty()
+    }
+    pub fn push(&mut self, val: T::Element) {
+        self.tail.push(val);
+    }
+    /// Returns the sequence pushed so far as a persistent tree.
+    pub fn snapshot(&mut self) -> PersistentLazyRBTree<T> {
+        if !self.tail.is_empty() {
+            let tail = self.tail.drain(..).collect();
+            self.tree = PersistentLazyRBTree::merge_owned(mem::take(&mut self.tree), tail);
+        }
+        self.tree.clone()
+    }
+}
+impl<T: LazyParam> Default for AppendLog<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T: LazyParam> From<PersistentLazyRBTree<T>> for AppendLog<T> {
+    fn from(tree: PersistentLazyRBTree<T>) -> Self {
+        Self { tree, tail: vec![] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{values, Sum};
+    use crate::{take_alloc_count, AppendLog, PersistentLazyRBTree};
+    use rand::Rng;
+
+    #[test]
+    fn snapshots_equal_naive_build() {
+        let mut rng = rand::thread_rng();
+        let mut log = AppendLog::new();
+        let mut naive = PersistentLazyRBTree::<Sum>::new();
+        let mut snapshots = vec![];
+        for i in 0..2000 {
+            log.push(Sum(i));
+            naive = naive.insert(naive.len(), Sum(i));
+            assert_eq!(naive.len(), log.len());
+            if rng.gen_range(0, 50) == 0 {
+                snapshots.push((log.snapshot(), values(&naive)));
+            }
+        }
+        snapshots.push((log.snapshot(), values(&naive)));
+        for (snapshot, expected) in snapshots {
+            assert_eq!(expected, values(&snapshot));
+        }
+    }
+
+    #[test]
+    fn fewer_allocations_than_push_back() {
+        take_alloc_count();
+        let mut naive = PersistentLazyRBTree::<Sum>::new();
+        for i in 0..2000 {
+            naive = naive.insert(naive.len(), Sum(i));
+        }
+        let naive_count = take_alloc_count();
+        let mut log = AppendLog::new();
+        for i in 0..2000 {
+            log.push(Sum(i));
+            if i % 100 == 0 {
+                log.snapshot();
+            }
+        }
+        let snapshot = log.snapshot();
+        assert!(take_alloc_count() * 4 < naive_count);
+        assert_eq!(values(&naive), values(&snapshot));
+    }
+}
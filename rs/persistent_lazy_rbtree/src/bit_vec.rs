@@ -0,0 +1,137 @@
+use crate::{LazyParam, Magma, Monoid, PersistentLazyRBTree, Semigroup, Unit};
+use std::iter::FromIterator;
+use std::ops::Add;
+
+// The number of set bits, which is the bit itself at a leaf.
+#[derive(Clone, Debug)]
+struct Ones(usize);
+impl Ones {
+    fn new(bit: bool) -> Self {
+        Self(bit as usize)
+    }
+}
+impl Add for Ones {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0)
+    }
+}
+impl Magma for Ones {}
+impl Semigroup for Ones {}
+impl Monoid for Ones {
+    fn zero() -> Self {
+        Self(0)
+    }
+}
+#[derive(Debug)]
+struct Bits;
+impl LazyParam for Bits {
+    type Element = Ones;
+    type Mapping = Unit;
+    fn apply(_: Self::Mapping, element: Self::Element) -> Self::Element {
+        element
+    }
+}
+
+/// A persistent bit vector with O(log n) rank and select, backed by the
+/// per-subtree popcounts.
+#[derive(Clone, Default)]
+pub struct PersistentBitVec {
+    tree: PersistentLazyRBTree<Bits>,
+}
+impl PersistentBitVec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<bool> {
+        self.tree.get(index).map(|ones| ones.0 == 1)
+    }
+    pub fn set(&self, index: usize, bit: bool) -> Self {
+        Self {
+            tree: self.tree.set(index, Ones::new(bit)),
+        }
+    }
+    pub fn insert(&self, index: usize, bit: bool) -> Self {
+        Self {
+            tree: self.tree.insert(index, Ones::new(bit)),
+        }
+    }
+    pub fn erase(&self, index: usize) -> Self {
+        Self {
+            tree: self.tree.erase(index),
+        }
+    }
+    pub fn count_ones(&self) -> usize {
+        self.tree.total().0
+    }
+    /// Returns the number of set bits in `[0, i)`.
+    pub fn rank1(&self, i: usize) -> usize {
+        assert!(i <= self.len());
+        self.tree.fold(0..i).0
+    }
+    /// Returns the index of the `k`-th set bit, counting from zero, or `None`
+    /// if there are at most `k` set bits.
+    pub fn select1(&self, k: usize) -> Option<usize> {
+        Some(self.tree.max_right(0, |ones| ones.0 <= k)).filter(|&i| i < self.len())
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = bool> + '_ {
+        self.tree.iter().map(|ones| ones.0 == 1)
+    }
+}
+impl FromIterator<bool> for PersistentBitVec {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        Self {
+            tree: iter.into_iter().map(Ones::new).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PersistentBitVec;
+    use rand::Rng;
+
+    #[test]
+    fn rank_select_equivalent_to_vec() {
+        let mut rng = rand::thread_rng();
+        let mut vec: Vec<bool> = (0..200).map(|_| rng.gen_range(0, 3) == 0).collect();
+        let mut bits: PersistentBitVec = vec.iter().cloned().collect();
+        for _ in 0..1000 {
+            match rng.gen_range(0, 3) {
+                0 => {
+                    let i = rng.gen_range(0, vec.len() + 1);
+                    let bit = rng.gen_range(0, 2) == 0;
+                    vec.insert(i, bit);
+                    bits = bits.insert(i, bit);
+                }
+                1 if !vec.is_empty() => {
+                    let i = rng.gen_range(0, vec.len());
+                    vec.remove(i);
+                    bits = bits.erase(i);
+                }
+                _ if !vec.is_empty() => {
+                    let i = rng.gen_range(0, vec.len());
+                    vec[i] = !vec[i];
+                    bits = bits.set(i, vec[i]);
+                }
+                _ => {}
+            }
+            let i = rng.gen_range(0, vec.len() + 1);
+            assert_eq!(vec[..i].iter().filter(|&&b| b).count(), bits.rank1(i));
+            let ones: Vec<usize> = (0..vec.len()).filter(|&i| vec[i]).collect();
+            assert_eq!(ones.len(), bits.count_ones());
+            let k = rng.gen_range(0, ones.len() + 2);
+            assert_eq!(ones.get(k).copied(), bits.select1(k));
+        }
+        assert!(bits.iter().eq(vec.iter().cloned()));
+        assert_eq!(vec.first().copied(), bits.get(0));
+        assert_eq!(None, PersistentBitVec::new().select1(0));
+        assert_eq!(0, PersistentBitVec::new().rank1(0));
+    }
+}
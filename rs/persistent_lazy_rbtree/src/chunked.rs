@@ -0,0 +1,536 @@
+use crate::Node::{Leaf, Tree};
+use crate::{LazyParam, Magma, Monoid, PersistentLazyRBTree, Semigroup};
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::ops::{Add, Bound, Index, Range, RangeBounds};
+use std::rc::Rc;
+
+#[derive(Clone, Debug)]
+struct Chunk<E> {
+    // Only present at leaves; aggregates keep just the length and the fold.
+    items: Option<Rc<[E]>>,
+    len: usize,
+    sum: E,
+}
+impl<E: Monoid> Chunk<E> {
+    fn new(items: Vec<E>) -> Self {
+        Self {
+            len: items.len(),
+            sum: items.iter().cloned().fold(E::zero(), |acc, x| acc + x),
+            items: Some(items.into()),
+        }
+    }
+    // Copies `items` straight into the shared buffer, with no `Vec` between.
+    fn from_slice(items: &[E]) -> Self
+    where
+        E: Copy,
+    {
+        Self {
+            len: items.len(),
+            sum: items.iter().fold(E::zero(), |acc, &x| acc + x),
+            items: Some(items.into()),
+        }
+    }
+    fn items(&self) -> &[E] {
+        self.items.as_deref().unwrap_or(&[])
+    }
+}
+impl<E: Monoid> Add for Chunk<E> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            items: None,
+            len: self.len + other.len,
+            sum: self.sum + other.sum,
+        }
+    }
+}
+impl<E: Monoid> Magma for Chunk<E> {}
+impl<E: Monoid> Semigroup for Chunk<E> {}
+impl<E: Monoid> Monoid for Chunk<E> {
+    fn zero() -> Self {
+        Self {
+            items: None,
+            len: 0,
+            sum: E::zero(),
+        }
+    }
+}
+#[derive(Debug)]
+struct Chunked<T>(PhantomData<T>);
+impl<T: LazyParam> LazyParam for Chunked<T> {
+    type Element = Chunk<T::Element>;
+    type Mapping = T::Mapping;
+    fn apply(mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+        Chunk {
+            items: element.items.map(|items| {
+                items
+                    .iter()
+                    .map(|x| T::apply(mapping.clone(), x.clone()))
+                    .collect()
+            }),
+            len: element.len,
+            sum: T::apply(mapping, element.sum),
+        }
+    }
+}
+
+/// A `PersistentLazyRBTree` whose leaves hold up to `B` elements each.
+///
+/// Packing elements into chunks cuts the node count by about a factor of `B`,
+/// at the cost of copying a chunk whenever an edit touches it. Every chunk
+/// but a lone one holds at least `B / 2` elements, so a tree of `n` elements
+/// has at most `2 * n / B + 1` chunks however it was edited.
+///
+/// The sequence operations have the same names, signatures and results as on
+/// `PersistentLazyRBTree`. Methods that inspect or reuse individual nodes,
+/// such as the diagnostics and the owned variants, exist only there.
+pub struct ChunkedLazyRBTree<T: LazyParam, const B: usize> {
+    tree: PersistentLazyRBTree<Chunked<T>>,
+}
+impl<T: LazyParam, const B: usize> ChunkedLazyRBTree<T, B> {
+    fn from(tree: PersistentLazyRBTree<Chunked<T>>) -> Self {
+        Self { tree }
+    }
+    fn from_chunks(chunks: impl IntoIterator<Item = Vec<T::Element>>) -> Self {
+        Self::from(chunks.into_iter().map(Chunk::new).collect())
+    }
+    /// Builds from a slice of `Copy` elements, copying each run straight from
+    /// the slice into a leaf. The length is known up front, so the elements
+    /// are spread evenly over `ceil(n / B)` leaves and none needs coalescing.
+    pub fn from_copy_slice(items: &[T::Element]) -> Self
+    where
+        T::Element: Copy,
+    {
+        assert!(B > 0);
+        let chunks = items.len().div_ceil(B);
+        let start = |i: usize| i * items.len() / chunks;
+        Self::from(
+            (0..chunks)
+                .map(|i| Chunk::from_slice(&items[start(i)..start(i + 1)]))
+                .collect(),
+        )
+    }
+    pub fn new() -> Self {
+        assert!(B > 0);
+        Self::from(PersistentLazyRBTree::new())
+    }
+    pub fn len(&self) -> usize {
+        self.tree.root.as_ref().map_or(0, |root| root.val().len)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&T::Element> {
+        (index < self.len()).then(|| &self[index])
+    }
+    // Returns the index of the chunk containing `index`, the offset within
+    // that chunk, and the chunk itself.
+    fn locate(&self, mut index: usize) -> (usize, usize, &Chunk<T::Element>) {
+        let mut node = self.tree.root.as_ref().unwrap();
+        let mut chunk_index = 0;
+        loop {
+            match node.as_ref() {
+                Leaf { val } => return (chunk_index, index, val),
+                Tree { left, right, .. } => {
+                    if index < left.val().len {
+                        node = left;
+                    } else {
+                        index -= left.val().len;
+                        chunk_index += left.len();
+                        node = right;
+                    }
+                }
+            }
+        }
+    }
+    // Replaces the chunks in `[l, r)` with `chunks`.
+    fn replace_chunks(&self, l: usize, r: usize, chunks: Vec<Vec<T::Element>>) -> Self {
+        let (ref left, ref right) = self.tree.split(l);
+        let (_, ref right) = right.split(r - l);
+        let mid = &Self::from_chunks(chunks.into_iter().filter(|chunk| !chunk.is_empty())).tree;
+        Self::from(PersistentLazyRBTree::merge(
+            &PersistentLazyRBTree::merge(left, mid),
+            right,
+        ))
+    }
+    // Splits `items` in half if it no longer fits in one chunk.
+    fn halve_if_full(mut items: Vec<T::Element>) -> Vec<Vec<T::Element>> {
+        if items.len() > B {
+            let tail = items.split_off(items.len() / 2);
+            vec![items, tail]
+        } else {
+            vec![items]
+        }
+    }
+    // Joins the chunks at `chunk_index` and `chunk_index + 1` if either has
+    // fallen below `B / 2` elements, halving the result again if it overflows.
+    fn coalesce(&self, chunk_index: usize) -> Self {
+        if chunk_index + 1 >= self.tree.len() {
+            return self.clone();
+        }
+        let (first, second) = (&self.tree[chunk_index], &self.tree[chunk_index + 1]);
+        if first.len.min(second.len) * 2 >= B {
+            return self.clone();
+        }
+        let mut items = first.items().to_vec();
+        items.extend_from_slice(second.items());
+        self.replace_chunks(chunk_index, chunk_index + 2, Self::halve_if_full(items))
+    }
+    pub fn merge(left: &Self, right: &Self) -> Self {
+        let seam = left.tree.len();
+        let merged = Self::from(PersistentLazyRBTree::merge(&left.tree, &right.tree));
+        if seam == 0 {
+            merged
+        } else {
+            merged.coalesce(seam - 1)
+        }
+    }
+    pub fn split(&self, index: usize) -> (Self, Self) {
+        assert!(index <= self.len());
+        if index == self.len() {
+            return (self.clone(), Self::new());
+        }
+        let (chunk_index, offset, chunk) = self.locate(index);
+        let (left, right) = self.tree.split(chunk_index);
+        if offset == 0 {
+            return (Self::from(left), Self::from(right));
+        }
+        let (_, right) = right.split(1);
+        let (head, tail) = chunk.items().split_at(offset);
+        // The partial chunks at the cut are merged into their neighbours.
+        (
+            Self::merge(&Self::from(left), &Self::from_chunks([head.to_vec()])),
+            Self::merge(&Self::from_chunks([tail.to_vec()]), &Self::from(right)),
+        )
+    }
+    /// Returns the elements within `range`, accepting any of the `..` forms.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Self {
+        let out_of_bounds = || -> usize {
+            panic!(
+                "slice range out of bounds for sequence of length {}",
+                self.len()
+            )
+        };
+        let l = match range.start_bound() {
+            Bound::Included(&l) => l,
+            Bound::Excluded(&l) => l.checked_add(1).unwrap_or_else(out_of_bounds),
+            Bound::Unbounded => 0,
+        };
+        let r = match range.end_bound() {
+            Bound::Included(&r) => r.checked_add(1).unwrap_or_else(out_of_bounds),
+            Bound::Excluded(&r) => r,
+            Bound::Unbounded => self.len(),
+        };
+        if l > r || r > self.len() {
+            out_of_bounds();
+        }
+        self.split(r).0.split(l).1
+    }
+    /// Returns the first `min(n, len())` elements.
+    pub fn take(&self, n: usize) -> Self {
+        self.split(n.min(self.len())).0
+    }
+    /// Returns all but the first `min(n, len())` elements.
+    pub fn skip(&self, n: usize) -> Self {
+        self.split(n.min(self.len())).1
+    }
+    /// Replaces `[l, r)` with the items of `replacement`.
+    pub fn splice_iter<I: IntoIterator<Item = T::Element>>(
+        &self,
+        l: usize,
+        r: usize,
+        replacement: I,
+    ) -> Self {
+        assert!(l <= r && r <= self.len());
+        let (left, right) = self.split(r);
+        let (left, _) = left.split(l);
+        Self::merge(
+            &Self::merge(&left, &replacement.into_iter().collect()),
+            &right,
+        )
+    }
+    /// Inserts `val` after every element not greater than it, assuming the
+    /// sequence is sorted by `cmp`.
+    pub fn insert_by(
+        &self,
+        val: T::Element,
+        cmp: impl Fn(&T::Element, &T::Element) -> Ordering,
+    ) -> Self {
+        let index = self.partition_point(|x| cmp(x, &val) != Ordering::Greater);
+        self.insert(index, val)
+    }
+    // Chunks are in order too, so the point lies in the last chunk whose
+    // first element satisfies `pred`.
+    fn partition_point(&self, pred: impl Fn(&T::Element) -> bool) -> usize {
+        let chunks = self.tree.partition_point(|chunk| pred(&chunk.items()[0]));
+        if chunks == 0 {
+            return 0;
+        }
+        let offset = self.tree.fold(0..chunks - 1).len;
+        offset + self.tree[chunks - 1].items().partition_point(|x| pred(x))
+    }
+    pub fn set(&self, index: usize, val: T::Element) -> Self {
+        assert!(index < self.len());
+        let (chunk_index, offset, chunk) = self.locate(index);
+        let mut items = chunk.items().to_vec();
+        items[offset] = val;
+        self.replace_chunks(chunk_index, chunk_index + 1, vec![items])
+    }
+    pub fn insert(&self, index: usize, val: T::Element) -> Self {
+        assert!(index <= self.len());
+        if self.is_empty() {
+            return Self::from_chunks([vec![val]]);
+        }
+        let (chunk_index, offset, chunk) = if index == self.len() {
+            let (chunk_index, offset, chunk) = self.locate(index - 1);
+            (chunk_index, offset + 1, chunk)
+        } else {
+            self.locate(index)
+        };
+        let mut items = chunk.items().to_vec();
+        items.insert(offset, val);
+        self.replace_chunks(chunk_index, chunk_index + 1, Self::halve_if_full(items))
+    }
+    pub fn erase(&self, index: usize) -> Self {
+        assert!(index < self.len());
+        let (chunk_index, offset, chunk) = self.locate(index);
+        let mut items = chunk.items().to_vec();
+        items.remove(offset);
+        let erased = self.replace_chunks(chunk_index, chunk_index + 1, vec![items]);
+        if chunk_index + 1 < erased.tree.len() {
+            erased.coalesce(chunk_index)
+        } else {
+            erased.coalesce(chunk_index.saturating_sub(1))
+        }
+    }
+    pub fn fold(&self, range: Range<usize>) -> T::Element {
+        let (left, _) = self.split(range.end);
+        let (_, mid) = left.split(range.start);
+        mid.tree
+            .root
+            .map(|root| root.val().sum.clone())
+            .unwrap_or(T::Element::zero())
+    }
+    /// The fold of the whole sequence, read from the root in O(1), or
+    /// `zero()` when empty.
+    pub fn total(&self) -> T::Element {
+        self.tree
+            .root
+            .as_ref()
+            .map_or_else(T::Element::zero, |root| root.val().sum.clone())
+    }
+    /// Returns the largest `r` such that `pred(&self.fold(l..r))` holds.
+    ///
+    /// `pred` must be monotone (once false, false for every longer range) and
+    /// hold for `T::Element::zero()`.
+    pub fn max_right(&self, l: usize, pred: impl Fn(&T::Element) -> bool) -> usize {
+        assert!(l <= self.len());
+        let rest = self.skip(l);
+        let chunks = rest.tree.max_right(0, |chunk| pred(&chunk.sum));
+        let prefix = rest.tree.fold(0..chunks);
+        let (mut r, mut acc) = (l + prefix.len, prefix.sum);
+        for x in rest.tree.get(chunks).map_or(&[][..], Chunk::items) {
+            acc = acc + x.clone();
+            if !pred(&acc) {
+                break;
+            }
+            r += 1;
+        }
+        r
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T::Element> {
+        self.tree.iter().flat_map(|chunk| chunk.items())
+    }
+    /// Iterates over `[l, r)`, starting from the chunk that holds `l`.
+    pub fn window_iter(&self, l: usize, r: usize) -> impl Iterator<Item = &T::Element> + '_ {
+        assert!(l <= r && r <= self.len());
+        let (chunk_index, offset) = if l < self.len() {
+            let (chunk_index, offset, _) = self.locate(l);
+            (chunk_index, offset)
+        } else {
+            (self.tree.len(), 0)
+        };
+        self.tree
+            .window_iter(chunk_index, self.tree.len())
+            .flat_map(|chunk| chunk.items())
+            .skip(offset)
+            .take(r - l)
+    }
+    pub fn to_vec(&self) -> Vec<T::Element> {
+        self.iter().cloned().collect()
+    }
+    #[cfg(test)]
+    fn chunk_count(&self) -> usize {
+        self.tree.len()
+    }
+}
+impl<T: LazyParam, const B: usize> Default for ChunkedLazyRBTree<T, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T: LazyParam, const B: usize> Clone for ChunkedLazyRBTree<T, B> {
+    fn clone(&self) -> Self {
+        Self::from(self.tree.clone())
+    }
+}
+/// Formats as the element sequence, like a `Vec`.
+impl<T: LazyParam, const B: usize> fmt::Debug for ChunkedLazyRBTree<T, B>
+where
+    T::Element: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+impl<T: LazyParam, const B: usize> PartialEq for ChunkedLazyRBTree<T, B>
+where
+    T::Element: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+impl<T: LazyParam, const B: usize> Eq for ChunkedLazyRBTree<T, B> where T::Element: Eq {}
+impl<T: LazyParam, const B: usize> Index<usize> for ChunkedLazyRBTree<T, B> {
+    type Output = T::Element;
+    fn index(&self, index: usize) -> &Self::Output {
+        assert!(index < self.len());
+        let (_, offset, chunk) = self.locate(index);
+        &chunk.items()[offset]
+    }
+}
+impl<T: LazyParam, const B: usize> FromIterator<T::Element> for ChunkedLazyRBTree<T, B> {
+    fn from_iter<I: IntoIterator<Item = T::Element>>(iter: I) -> Self {
+        assert!(B > 0);
+        let mut iter = iter.into_iter();
+        let chunked = Self::from_chunks(std::iter::from_fn(|| {
+            let chunk: Vec<_> = iter.by_ref().take(B).collect();
+            Some(chunk).filter(|chunk| !chunk.is_empty())
+        }));
+        // Only the last chunk can be short.
+        chunked.coalesce(chunked.tree.len().saturating_sub(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{random_tree, values, Keyed, Sum};
+    use crate::{ChunkedLazyRBTree, PersistentLazyRBTree};
+    use rand::Rng;
+
+    #[test]
+    fn equivalent_to_per_element_tree() {
+        let mut rng = rand::thread_rng();
+        let (_, mut rbtree) = random_tree(300, 100);
+        let mut chunked: ChunkedLazyRBTree<Sum, 8> = rbtree.iter().cloned().collect();
+        for _ in 0..3000 {
+            match rng.gen_range(0, 8) {
+                0 => {
+                    let i = rng.gen_range(0, rbtree.len() + 1);
+                    let x = rng.gen_range(0, 100);
+                    rbtree = rbtree.insert(i, Sum(x));
+                    chunked = chunked.insert(i, Sum(x));
+                }
+                1 if !rbtree.is_empty() => {
+                    let i = rng.gen_range(0, rbtree.len());
+                    rbtree = rbtree.erase(i);
+                    chunked = chunked.erase(i);
+                }
+                2 => {
+                    let i = rng.gen_range(0, rbtree.len() + 1);
+                    let (left, right) = rbtree.split(i);
+                    rbtree = PersistentLazyRBTree::merge(&right, &left);
+                    let (left, right) = chunked.split(i);
+                    assert_eq!(i, left.len());
+                    chunked = ChunkedLazyRBTree::merge(&right, &left);
+                }
+                3 if !rbtree.is_empty() => {
+                    let i = rng.gen_range(0, rbtree.len());
+                    let x = rng.gen_range(0, 100);
+                    rbtree = rbtree.set(i, Sum(x));
+                    chunked = chunked.set(i, Sum(x));
+                }
+                4 => {
+                    let l = rng.gen_range(0, rbtree.len() + 1);
+                    let r = rng.gen_range(l, (l + 20).min(rbtree.len()) + 1);
+                    let items: Vec<_> = (0..rng.gen_range(0, 20)).map(Sum).collect();
+                    rbtree = rbtree.splice_iter(l, r, items.iter().cloned());
+                    chunked = chunked.splice_iter(l, r, items);
+                }
+                5 => {
+                    let l = rng.gen_range(0, rbtree.len() + 1);
+                    let limit = rng.gen_range(0, 1000);
+                    assert_eq!(
+                        rbtree.max_right(l, |x| x.0 <= limit),
+                        chunked.max_right(l, |x| x.0 <= limit)
+                    );
+                }
+                6 => {
+                    let l = rng.gen_range(0, rbtree.len() + 1);
+                    let r = rng.gen_range(l, rbtree.len() + 1);
+                    assert!(rbtree.window_iter(l, r).eq(chunked.window_iter(l, r)));
+                    assert!(rbtree.slice(l..r).iter().eq(chunked.slice(l..r).iter()));
+                }
+                _ => {
+                    let l = rng.gen_range(0, rbtree.len() + 1);
+                    let r = rng.gen_range(l, rbtree.len() + 1);
+                    assert_eq!(rbtree.fold(l..r), chunked.fold(l..r));
+                }
+            }
+            assert_eq!(rbtree.len(), chunked.len());
+            assert!(chunked.chunk_count() <= 2 * chunked.len() / 8 + 2);
+            let i = rng.gen_range(0, rbtree.len() + 1);
+            assert_eq!(rbtree.get(i), chunked.get(i));
+        }
+        assert_eq!(
+            values(&rbtree),
+            chunked.iter().map(|x| x.0).collect::<Vec<_>>()
+        );
+        assert!(chunked.iter().rev().eq(rbtree.rev()));
+        assert_eq!(rbtree.total(), chunked.total());
+        assert_eq!(rbtree.to_vec(), chunked.to_vec());
+    }
+
+    #[test]
+    fn insert_by() {
+        let mut rng = rand::thread_rng();
+        let cmp = |a: &Sum, b: &Sum| (a.0 / 10).cmp(&(b.0 / 10));
+        let mut rbtree = PersistentLazyRBTree::<Sum>::new();
+        let mut chunked = ChunkedLazyRBTree::<Sum, 8>::new();
+        for _ in 0..500 {
+            let x = rng.gen_range(0, 100);
+            rbtree = rbtree.insert_by(Sum(x), cmp);
+            chunked = chunked.insert_by(Sum(x), cmp);
+        }
+        assert_eq!(rbtree.to_vec(), chunked.to_vec());
+    }
+
+    #[test]
+    fn packs_elements() {
+        let chunked: ChunkedLazyRBTree<Sum, 16> = (0..1000).map(Sum).collect();
+        assert_eq!(63, chunked.chunk_count());
+        let chunked = (0..1000).fold(ChunkedLazyRBTree::<Sum, 16>::new(), |acc, x| {
+            acc.insert(acc.len(), Sum(x))
+        });
+        assert!(chunked.chunk_count() <= 1000 / 8 + 1);
+        assert!(chunked.iter().map(|x| x.0).eq(0..1000));
+    }
+
+    #[test]
+    fn from_copy_slice() {
+        let items: Vec<_> = (0..1000).map(|x| Keyed(x, x as usize)).collect();
+        let chunked = ChunkedLazyRBTree::<Keyed, 16>::from_copy_slice(&items);
+        let generic: PersistentLazyRBTree<Keyed> = items.iter().copied().collect();
+        assert!(chunked.iter().map(|x| x.1).eq(generic.iter().map(|x| x.1)));
+        let (expected, folded) = (generic.fold(10..900), chunked.fold(10..900));
+        assert_eq!((expected.0, expected.1), (folded.0, folded.1));
+        assert_eq!(63, chunked.chunk_count());
+        assert!(chunked.tree.iter().all(|chunk| chunk.len >= 8));
+        assert!(chunked.tree.node_count() * 10 < generic.node_count());
+        assert!(ChunkedLazyRBTree::<Keyed, 16>::from_copy_slice(&[]).is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{LazyParam, PersistentLazyRBTree};
+use std::io::{self, Read, Write};
+
+/// Types that can be written in a fixed little-endian binary layout.
+pub trait ToBytes {
+    fn write_bytes(&self, w: &mut impl Write) -> io::Result<()>;
+}
+/// Types that can be read back from the layout written by `ToBytes`.
+pub trait FromBytes: Sized {
+    fn read_bytes(r: &mut impl Read) -> io::Result<Self>;
+}
+macro_rules! impl_bytes {
+    ($($t:ty),*) => {$(
+        impl ToBytes for $t {
+            fn write_bytes(&self, w: &mut impl Write) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+        }
+        impl FromBytes for $t {
+            fn read_bytes(r: &mut impl Read) -> io::Result<Self> {
+                let mut buf = [0; std::mem::size_of::<$t>()];
+                r.read_exact(&mut buf)?;
+                Ok(Self::from_le_bytes(buf))
+            }
+        }
+    )*};
+}
+impl_bytes!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl<T: LazyParam> PersistentLazyRBTree<T> {
+    /// Writes the length as a `u64` followed by every element in order.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()>
+    where
+        T::Element: ToBytes,
+    {
+        (self.len() as u64).write_bytes(w)?;
+        self.iter().try_for_each(|val| val.write_bytes(w))
+    }
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self>
+    where
+        T::Element: FromBytes,
+    {
+        let len = u64::read_bytes(r)?;
+        (0..len).map(|_| T::Element::read_bytes(r)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::Sum;
+    use crate::{FromBytes, PersistentLazyRBTree, ToBytes};
+    use std::io::{self, Read, Write};
+
+    impl ToBytes for Sum {
+        fn write_bytes(&self, w: &mut impl Write) -> io::Result<()> {
+            self.0.write_bytes(w)
+        }
+    }
+    impl FromBytes for Sum {
+        fn read_bytes(r: &mut impl Read) -> io::Result<Self> {
+            i64::read_bytes(r).map(Sum)
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for n in [0, 1, 2, 100] {
+            let rbtree: PersistentLazyRBTree<Sum> = (0..n).map(|x| Sum(x * x - 50)).collect();
+            let mut buf = vec![];
+            rbtree.write_to(&mut buf).unwrap();
+            assert_eq!(8 + 8 * n as usize, buf.len());
+            let read = PersistentLazyRBTree::<Sum>::read_from(&mut buf.as_slice()).unwrap();
+            assert!(rbtree.iter().eq(read.iter()));
+        }
+        let mut truncated: &[u8] = &[3, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert!(PersistentLazyRBTree::<Sum>::read_from(&mut truncated).is_err());
+    }
+}
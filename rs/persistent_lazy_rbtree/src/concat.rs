@@ -0,0 +1,49 @@
+use crate::{LazyParam, PersistentLazyRBTree};
+
+/// Collects trees to be concatenated and merges them all at once in
+/// `finish`, pairing neighbors round by round instead of folding from the
+/// left.
+pub struct Concat<T: LazyParam> {
+    parts: Vec<PersistentLazyRBTree<T>>,
+}
+impl<T: LazyParam> Concat<T> {
+    pub fn new() -> Self {
+        Self { parts: vec![] }
+    }
+    pub fn append(&mut self, tree: &PersistentLazyRBTree<T>) {
+        if !tree.is_empty() {
+            self.parts.push(tree.clone());
+        }
+    }
+    pub fn finish(self) -> PersistentLazyRBTree<T> {
+        PersistentLazyRBTree::merge_all(self.parts)
+    }
+}
+impl<T: LazyParam> Default for Concat<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{random_tree, values, Sum};
+    use crate::{Concat, PersistentLazyRBTree};
+
+    #[test]
+    fn finish_equals_fold() {
+        let parts: Vec<_> = (0..100).map(|i| random_tree(i % 7, 100).1).collect();
+        let mut concat = Concat::new();
+        for part in &parts {
+            concat.append(part);
+        }
+        let folded = parts
+            .iter()
+            .fold(PersistentLazyRBTree::<Sum>::new(), |acc, part| {
+                PersistentLazyRBTree::merge(&acc, part)
+            });
+        let finished = concat.finish();
+        assert_eq!(values(&folded), values(&finished));
+        assert!(Concat::<Sum>::new().finish().is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{LazyParam, PersistentLazyRBTree};
+use std::ops::Deref;
+
+/// A mutable handle over a `PersistentLazyRBTree`.
+///
+/// Each edit replaces the held version, so snapshots taken earlier are left
+/// untouched. Read-only methods are available through `Deref`.
+pub struct CowSeq<T: LazyParam> {
+    tree: PersistentLazyRBTree<T>,
+}
+impl<T: LazyParam> CowSeq<T> {
+    pub fn new() -> Self {
+        Self::from(PersistentLazyRBTree::new())
+    }
+    pub fn push(&mut self, val: T::Element) {
+        self.insert(self.len(), val);
+    }
+    pub fn insert(&mut self, index: usize, val: T::Element) {
+        self.tree = self.tree.insert(index, val);
+    }
+    pub fn erase(&mut self, index: usize) {
+        self.tree = self.tree.erase(index);
+    }
+    pub fn set(&mut self, index: usize, val: T::Element) {
+        self.tree = self.tree.set(index, val);
+    }
+    /// Returns the current version, which later edits won't affect.
+    pub fn snapshot(&self) -> PersistentLazyRBTree<T> {
+        self.tree.clone()
+    }
+}
+impl<T: LazyParam> From<PersistentLazyRBTree<T>> for CowSeq<T> {
+    fn from(tree: PersistentLazyRBTree<T>) -> Self {
+        Self { tree }
+    }
+}
+impl<T: LazyParam> Default for CowSeq<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T: LazyParam> Clone for CowSeq<T> {
+    fn clone(&self) -> Self {
+        Self::from(self.snapshot())
+    }
+}
+impl<T: LazyParam> Deref for CowSeq<T> {
+    type Target = PersistentLazyRBTree<T>;
+    fn deref(&self) -> &Self::Target {
+        &self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{values, Sum};
+    use crate::CowSeq;
+
+    #[test]
+    fn snapshots_survive_edits() {
+        let mut seq = CowSeq::new();
+        for x in 0..10 {
+            seq.push(Sum(x));
+        }
+        let before = seq.snapshot();
+        seq.insert(0, Sum(-1));
+        seq.set(5, Sum(100));
+        seq.erase(10);
+        assert_eq!((0..10).collect::<Vec<_>>(), values(&before));
+        assert_eq!(vec![-1, 0, 1, 2, 3, 100, 5, 6, 7, 8], values(&seq));
+        assert_eq!(Sum(100), seq[5]);
+    }
+}
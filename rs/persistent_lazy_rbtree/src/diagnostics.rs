@@ -0,0 +1,261 @@
+use crate::{Color, LazyParam, Node, PersistentLazyRBTree};
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+use std::rc::Rc;
+
+type NodeIds<T> = HashMap<*const Node<T>, usize>;
+
+impl<T: LazyParam> PersistentLazyRBTree<T> {
+    // Every distinct node reachable from the root in preorder, along with the
+    // position of each node in that order.
+    fn nodes(&self) -> (Vec<&Rc<Node<T>>>, NodeIds<T>) {
+        let mut nodes = vec![];
+        let mut ids = HashMap::new();
+        let mut stack: Vec<&Rc<Node<T>>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            if ids.contains_key(&Rc::as_ptr(node)) {
+                continue;
+            }
+            ids.insert(Rc::as_ptr(node), nodes.len());
+            nodes.push(node);
+            if let Node::Tree { left, right, .. } = node.as_ref() {
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+        (nodes, ids)
+    }
+    /// The number of edges on the longest root-to-leaf path, or 0 when empty.
+    pub fn height(&self) -> usize {
+        fn height<T: LazyParam>(node: &Node<T>) -> usize {
+            match node {
+                Node::Leaf { .. } => 0,
+                Node::Tree { left, right, .. } => 1 + height(left).max(height(right)),
+            }
+        }
+        self.root.as_deref().map_or(0, height)
+    }
+    /// `height()` relative to the optimum `log2(len())`, so that values near
+    /// 1.0 mean a well balanced tree. Returns 0.0 for an empty tree.
+    pub fn balance_factor(&self) -> f64 {
+        if self.is_empty() {
+            0.0
+        } else {
+            self.height() as f64 / (self.len() as f64).log2().max(1.0)
+        }
+    }
+    /// The number of leaves and internal nodes, counting shared subtrees once
+    /// per occurrence. A valid tree has `len()` leaves and `len() - 1`
+    /// internal nodes, or none when empty.
+    pub fn counts(&self) -> (usize, usize) {
+        fn counts<T: LazyParam>(node: &Node<T>) -> (usize, usize) {
+            match node {
+                Node::Leaf { .. } => (1, 0),
+                Node::Tree { left, right, .. } => {
+                    let (left, right) = (counts(left), counts(right));
+                    (left.0 + right.0, left.1 + right.1 + 1)
+                }
+            }
+        }
+        self.root.as_deref().map_or((0, 0), counts)
+    }
+    /// The number of distinct nodes reachable from the root.
+    pub fn node_count(&self) -> usize {
+        self.nodes().0.len()
+    }
+    /// The number of distinct nodes of `b` that are also reachable from `a`.
+    pub fn shared_nodes(a: &Self, b: &Self) -> usize {
+        let (_, ids) = a.nodes();
+        let (nodes, _) = b.nodes();
+        nodes
+            .iter()
+            .filter(|node| ids.contains_key(&Rc::as_ptr(node)))
+            .count()
+    }
+    /// Panics unless `after` has at most `max_new_nodes` nodes that `before`
+    /// lacks, to catch edits that rebuild more than their paths.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn assert_shares_after_edit(before: &Self, after: &Self, max_new_nodes: usize) {
+        let new_nodes = after.node_count() - Self::shared_nodes(before, after);
+        assert!(
+            new_nodes <= max_new_nodes,
+            "edit created {} new nodes, expected at most {} (height {} after)",
+            new_nodes,
+            max_new_nodes,
+            after.height()
+        );
+    }
+    /// Renders the node structure in Graphviz DOT, with internal nodes labeled
+    /// by rank and leaves by value. Subtrees shared within the tree are drawn
+    /// once.
+    pub fn to_dot(&self) -> String
+    where
+        T::Element: Debug,
+    {
+        let (nodes, ids) = self.nodes();
+        let mut dot = String::from("digraph {\n");
+        for (id, node) in nodes.iter().enumerate() {
+            let (label, color) = match node.as_ref() {
+                Node::Leaf { val } => (format!("{:?}", val), Color::Black),
+                Node::Tree { rank, color, .. } => (rank.to_string(), *color),
+            };
+            let color = match color {
+                Color::Red => "red",
+                Color::Black => "black",
+            };
+            writeln!(
+                dot,
+                "    {} [label={:?}, color={}, fontcolor={}];",
+                id, label, color, color
+            )
+            .unwrap();
+            if let Node::Tree { left, right, .. } = node.as_ref() {
+                for child in [left, right] {
+                    writeln!(dot, "    {} -> {};", id, ids[&Rc::as_ptr(child)]).unwrap();
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{random_tree, Sum};
+    use crate::PersistentLazyRBTree;
+    use rand::Rng;
+
+    #[test]
+    fn to_dot() {
+        assert_eq!(
+            "digraph {\n}\n",
+            PersistentLazyRBTree::<Sum>::new().to_dot()
+        );
+        let rbtree: PersistentLazyRBTree<Sum> = (0..3).map(Sum).collect();
+        assert_eq!(
+            "digraph {
+    0 [label=\"1\", color=black, fontcolor=black];
+    0 -> 1;
+    0 -> 2;
+    1 [label=\"Sum(0)\", color=black, fontcolor=black];
+    2 [label=\"1\", color=red, fontcolor=red];
+    2 -> 3;
+    2 -> 4;
+    3 [label=\"Sum(1)\", color=black, fontcolor=black];
+    4 [label=\"Sum(2)\", color=black, fontcolor=black];
+}
+",
+            rbtree.to_dot()
+        );
+
+        let (vec, rbtree) = random_tree(300, 100);
+        let dot = rbtree.to_dot();
+        let lines: Vec<&str> = dot.lines().collect();
+        assert_eq!(Some(&"digraph {"), lines.first());
+        assert_eq!(Some(&"}"), lines.last());
+        let nodes = lines.iter().filter(|line| line.contains("[label=")).count();
+        let edges = lines.iter().filter(|line| line.contains(" -> ")).count();
+        assert_eq!(rbtree.node_count(), nodes);
+        assert_eq!(rbtree.node_count() - 1, edges);
+        assert_eq!(2 * vec.len() - 1, rbtree.node_count());
+        assert!(lines
+            .iter()
+            .all(|line| line.ends_with(';') || line.ends_with('{') || *line == "}"));
+    }
+
+    #[test]
+    fn balance_factor() {
+        assert_eq!(0.0, PersistentLazyRBTree::<Sum>::new().balance_factor());
+        let rbtree: PersistentLazyRBTree<Sum> = (0..1024).map(Sum).collect();
+        assert_eq!(10, rbtree.height());
+        assert_eq!(1.0, rbtree.balance_factor());
+        let rbtree = (0..1024).fold(PersistentLazyRBTree::<Sum>::new(), |acc, x| {
+            acc.insert(acc.len(), Sum(x))
+        });
+        assert!(rbtree.balance_factor() <= 2.0);
+        let (_, rbtree) = random_tree(1000, 100);
+        assert!(rbtree.balance_factor() <= 2.0);
+    }
+
+    #[test]
+    fn height_stays_logarithmic() {
+        assert_eq!(0, PersistentLazyRBTree::<Sum>::new().height());
+        let rbtree: PersistentLazyRBTree<Sum> = (0..1024).map(Sum).collect();
+        assert_eq!(10, rbtree.height());
+
+        let mut rng = rand::thread_rng();
+        let (_, mut rbtree) = random_tree(500, 100);
+        for _ in 0..2000 {
+            let i = rng.gen_range(0, rbtree.len() + 1);
+            let (left, right) = rbtree.split(i);
+            let (_, other) = random_tree(rng.gen_range(0, 5), 100);
+            rbtree = match rng.gen_range(0, 3) {
+                0 => PersistentLazyRBTree::merge(&right, &left),
+                1 => {
+                    PersistentLazyRBTree::merge(&PersistentLazyRBTree::merge(&left, &other), &right)
+                }
+                _ => PersistentLazyRBTree::merge(&left, &right.skip(1)),
+            };
+            let bound = 2 * (usize::BITS - rbtree.len().leading_zeros()) as usize + 1;
+            assert!(rbtree.height() <= bound);
+        }
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!((0, 0), PersistentLazyRBTree::<Sum>::new().counts());
+        let mut rng = rand::thread_rng();
+        let (_, mut rbtree) = random_tree(200, 100);
+        for _ in 0..200 {
+            let i = rng.gen_range(0, rbtree.len() + 1);
+            let (left, right) = rbtree.split(i);
+            rbtree = PersistentLazyRBTree::merge(&right, &left);
+            if rng.gen_range(0, 2) == 0 {
+                rbtree = PersistentLazyRBTree::merge(&rbtree, &left.take(5));
+            }
+            assert_eq!((rbtree.len(), rbtree.len() - 1), rbtree.counts());
+            assert!(rbtree.check_invariants());
+        }
+    }
+
+    #[test]
+    fn shares_after_edit() {
+        let mut rng = rand::thread_rng();
+        let (_, mut rbtree) = random_tree(1000, 100);
+        for _ in 0..300 {
+            // Each edit rebuilds O(1) paths, each at most the height long.
+            let bound = 6 * (rbtree.height() + 1);
+            let i = rng.gen_range(0, rbtree.len());
+            let edited = match rng.gen_range(0, 3) {
+                0 => rbtree.insert(i, Sum(-1)),
+                1 => rbtree.erase(i),
+                _ => {
+                    let edited = rbtree.set(i, Sum(-1));
+                    PersistentLazyRBTree::assert_shares_after_edit(
+                        &rbtree,
+                        &edited,
+                        rbtree.height() + 1,
+                    );
+                    edited
+                }
+            };
+            PersistentLazyRBTree::assert_shares_after_edit(&rbtree, &edited, bound);
+            rbtree = edited;
+        }
+        let empty = PersistentLazyRBTree::<Sum>::new();
+        assert_eq!(0, PersistentLazyRBTree::shared_nodes(&empty, &rbtree));
+        assert_eq!(
+            rbtree.node_count(),
+            PersistentLazyRBTree::shared_nodes(&rbtree, &rbtree.clone())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "edit created")]
+    fn shares_after_edit_catches_rebuilds() {
+        let (_, rbtree) = random_tree(100, 100);
+        let rebuilt: PersistentLazyRBTree<Sum> = rbtree.iter().cloned().collect();
+        PersistentLazyRBTree::assert_shares_after_edit(&rbtree, &rebuilt, 50);
+    }
+}
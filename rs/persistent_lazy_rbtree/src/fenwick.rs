@@ -0,0 +1,82 @@
+use crate::{LazyParam, Monoid, PersistentLazyRBTree};
+use std::iter::FromIterator;
+
+/// A persistent stand-in for a Fenwick tree: point updates and range sums in
+/// O(log n), where every update returns a new version and old versions stay
+/// valid.
+pub struct FenwickSeq<T: LazyParam> {
+    tree: PersistentLazyRBTree<T>,
+}
+impl<T: LazyParam> FenwickSeq<T> {
+    fn from(tree: PersistentLazyRBTree<T>) -> Self {
+        Self { tree }
+    }
+    /// `n` zeros.
+    pub fn new(n: usize) -> Self {
+        Self::from(PersistentLazyRBTree::from_exact_iter(
+            (0..n).map(|_| T::Element::zero()),
+        ))
+    }
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+    /// Adds `delta` to the element at `i`.
+    pub fn add(&self, i: usize, delta: T::Element) -> Self {
+        Self::from(self.tree.set(i, self.tree[i].clone() + delta))
+    }
+    /// The sum of `[l, r)`.
+    pub fn sum(&self, l: usize, r: usize) -> T::Element {
+        assert!(l <= r && r <= self.len());
+        self.tree.fold(l..r)
+    }
+}
+impl<T: LazyParam> Clone for FenwickSeq<T> {
+    fn clone(&self) -> Self {
+        Self::from(self.tree.clone())
+    }
+}
+impl<T: LazyParam> FromIterator<T::Element> for FenwickSeq<T> {
+    fn from_iter<I: IntoIterator<Item = T::Element>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::Sum;
+    use crate::FenwickSeq;
+    use rand::Rng;
+
+    #[test]
+    fn equivalent_to_prefix_sums() {
+        let mut rng = rand::thread_rng();
+        let mut fenwick = FenwickSeq::<Sum>::new(100);
+        let mut vec = vec![0; 100];
+        let mut versions = vec![];
+        for _ in 0..1000 {
+            let i = rng.gen_range(0, vec.len());
+            let delta = rng.gen_range(-50, 50);
+            fenwick = fenwick.add(i, Sum(delta));
+            vec[i] += delta;
+            let prefix: Vec<i64> = std::iter::once(0)
+                .chain(vec.iter().scan(0, |acc, x| {
+                    *acc += x;
+                    Some(*acc)
+                }))
+                .collect();
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            assert_eq!(Sum(prefix[r] - prefix[l]), fenwick.sum(l, r));
+            versions.push((fenwick.clone(), prefix[vec.len()]));
+        }
+        for (fenwick, total) in versions {
+            assert_eq!(Sum(total), fenwick.sum(0, fenwick.len()));
+        }
+        let fenwick: FenwickSeq<Sum> = (1..=4).map(Sum).collect();
+        assert_eq!(Sum(5), fenwick.sum(1, 3));
+        assert!(FenwickSeq::<Sum>::new(0).is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{LazyParam, PersistentLazyRBTree};
+
+/// A persistent double-ended priority queue backed by a sorted
+/// `PersistentLazyRBTree`. Every edit returns a new version.
+pub struct PersistentHeap<T: LazyParam> {
+    tree: PersistentLazyRBTree<T>,
+}
+impl<T: LazyParam> PersistentHeap<T>
+where
+    T::Element: Ord,
+{
+    fn from(tree: PersistentLazyRBTree<T>) -> Self {
+        Self { tree }
+    }
+    pub fn new() -> Self {
+        Self::from(PersistentLazyRBTree::new())
+    }
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+    pub fn push(&self, x: T::Element) -> Self {
+        Self::from(self.tree.insert_by(x, Ord::cmp))
+    }
+    pub fn peek_min(&self) -> Option<&T::Element> {
+        self.tree.get(0)
+    }
+    pub fn peek_max(&self) -> Option<&T::Element> {
+        self.tree
+            .len()
+            .checked_sub(1)
+            .and_then(|i| self.tree.get(i))
+    }
+    /// Removes the minimum, or returns `None` if the heap is empty.
+    pub fn pop_min(&self) -> Option<Self> {
+        (!self.is_empty()).then(|| Self::from(self.tree.skip(1)))
+    }
+    /// Removes the maximum, or returns `None` if the heap is empty.
+    pub fn pop_max(&self) -> Option<Self> {
+        (!self.is_empty()).then(|| Self::from(self.tree.take(self.len() - 1)))
+    }
+}
+impl<T: LazyParam> Default for PersistentHeap<T>
+where
+    T::Element: Ord,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T: LazyParam> Clone for PersistentHeap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::Sum;
+    use crate::PersistentHeap;
+    use rand::Rng;
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    #[test]
+    fn equivalent_to_binary_heap() {
+        let mut rng = rand::thread_rng();
+        let mut heap = PersistentHeap::<Sum>::new();
+        let mut reference = BinaryHeap::new();
+        let mut versions = vec![];
+        for _ in 0..2000 {
+            if rng.gen_range(0, 3) == 0 {
+                assert_eq!(heap.is_empty(), heap.pop_min().is_none());
+                heap = heap.pop_min().unwrap_or(heap);
+                reference.pop();
+            } else {
+                let x = rng.gen_range(0, 100);
+                heap = heap.push(Sum(x));
+                reference.push(Reverse(x));
+            }
+            assert_eq!(reference.len(), heap.len());
+            assert_eq!(reference.peek().map(|x| x.0), heap.peek_min().map(|x| x.0));
+            versions.push((heap.clone(), reference.clone()));
+        }
+        for (heap, reference) in versions {
+            let max = reference.iter().map(|x| x.0).max();
+            assert_eq!(max, heap.peek_max().map(|x| x.0));
+            let popped = heap.pop_max().map(|heap| heap.len());
+            assert_eq!(reference.len().checked_sub(1), popped);
+        }
+    }
+}
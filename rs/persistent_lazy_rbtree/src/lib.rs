@@ -1,17 +1,165 @@
-use std::iter::{DoubleEndedIterator, FromIterator, FusedIterator, IntoIterator};
-use std::ops::{Add, Index, Range};
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::{DoubleEndedIterator, FromIterator, FusedIterator, IntoIterator, Rev};
+use std::mem;
+use std::ops::{Add, Bound, Deref, Index, Range, RangeBounds};
 use std::rc::Rc;
 
+mod append_log;
+mod bit_vec;
+mod chunked;
+mod codec;
+mod concat;
+mod cow;
+mod diagnostics;
+mod fenwick;
+mod heap;
+mod multiset;
+mod rope;
+
+pub use append_log::AppendLog;
+pub use bit_vec::PersistentBitVec;
+pub use chunked::ChunkedLazyRBTree;
+pub use codec::{FromBytes, ToBytes};
+pub use concat::Concat;
+pub use cow::CowSeq;
+pub use fenwick::FenwickSeq;
+pub use heap::PersistentHeap;
+pub use multiset::PersistentMultiset;
+pub use rope::Rope;
+
 pub trait Magma: Sized + Add<Output = Self> + Clone {}
 pub trait Semigroup: Magma {}
 pub trait Monoid: Semigroup {
     fn zero() -> Self;
 }
+/// A monoid whose fold also keeps the least and greatest key folded into it,
+/// so that range extrema can be read from the cached folds of subtrees.
+pub trait Extrema: Monoid {
+    type Key: Ord;
+    /// The least key folded into `self`, or `None` for `zero()`.
+    fn min_key(&self) -> Option<&Self::Key>;
+    /// The greatest key folded into `self`, or `None` for `zero()`.
+    fn max_key(&self) -> Option<&Self::Key>;
+}
 pub trait LazyParam {
     type Element: Monoid;
     type Mapping: Monoid;
     fn apply(mapping: Self::Mapping, element: Self::Element) -> Self::Element;
 }
+/// The product of two monoids, combined componentwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pair<A, B>(pub A, pub B);
+impl<A: Add<Output = A>, B: Add<Output = B>> Add for Pair<A, B> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Pair(self.0 + other.0, self.1 + other.1)
+    }
+}
+impl<A: Magma, B: Magma> Magma for Pair<A, B> {}
+impl<A: Semigroup, B: Semigroup> Semigroup for Pair<A, B> {}
+impl<A: Monoid, B: Monoid> Monoid for Pair<A, B> {
+    fn zero() -> Self {
+        Pair(A::zero(), B::zero())
+    }
+}
+impl<A: LazyParam, B: LazyParam> LazyParam for (A, B) {
+    type Element = Pair<A::Element, B::Element>;
+    type Mapping = Pair<A::Mapping, B::Mapping>;
+    fn apply(mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+        Pair(
+            A::apply(mapping.0, element.0),
+            B::apply(mapping.1, element.1),
+        )
+    }
+}
+#[derive(Clone, Debug, Copy)]
+struct Unit;
+impl Add for Unit {
+    type Output = Self;
+    fn add(self, _: Self) -> Self {
+        Unit
+    }
+}
+impl Magma for Unit {}
+impl Semigroup for Unit {}
+impl Monoid for Unit {
+    fn zero() -> Self {
+        Unit
+    }
+}
+#[cfg(any(test, feature = "alloc-count"))]
+thread_local! {
+    static NODE_ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+/// Returns the number of nodes allocated on this thread since the last call,
+/// and resets the count.
+#[cfg(any(test, feature = "alloc-count"))]
+pub fn take_alloc_count() -> usize {
+    NODE_ALLOCATIONS.with(|count| count.replace(0))
+}
+/// How many non-trivial splits and merges ran on a thread, and how many nodes
+/// they constructed, as returned by `take_op_stats`.
+#[cfg(any(test, feature = "metrics"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub splits: usize,
+    pub split_nodes: usize,
+    pub merges: usize,
+    pub merge_nodes: usize,
+}
+#[cfg(any(test, feature = "metrics"))]
+thread_local! {
+    static OP_STATS: std::cell::Cell<OpStats> = std::cell::Cell::new(OpStats::default());
+    static NODES_BUILT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+/// Returns the split and merge counts on this thread since the last call, and
+/// resets them.
+#[cfg(any(test, feature = "metrics"))]
+pub fn take_op_stats() -> OpStats {
+    OP_STATS.with(|stats| stats.take())
+}
+// Nodes stepped through by `InOrder` and the range folds, so tests can
+// check how much of the tree a read touches.
+#[cfg(test)]
+thread_local! {
+    static NODE_VISITS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+#[derive(Clone, Copy)]
+enum OpKind {
+    Split,
+    Merge,
+}
+// Runs `f`, a split or merge on nodes, and records it in `OP_STATS`.
+#[cfg(any(test, feature = "metrics"))]
+fn record<R>(kind: OpKind, f: impl FnOnce() -> R) -> R {
+    let before = NODES_BUILT.with(std::cell::Cell::get);
+    let res = f();
+    let nodes = NODES_BUILT.with(std::cell::Cell::get) - before;
+    OP_STATS.with(|stats| {
+        let mut new = stats.get();
+        match kind {
+            OpKind::Split => {
+                new.splits += 1;
+                new.split_nodes += nodes;
+            }
+            OpKind::Merge => {
+                new.merges += 1;
+                new.merge_nodes += nodes;
+            }
+        }
+        stats.set(new);
+    });
+    res
+}
+#[cfg(not(any(test, feature = "metrics")))]
+fn record<R>(_: OpKind, f: impl FnOnce() -> R) -> R {
+    f()
+}
 #[derive(Clone, Debug, Copy)]
 enum Color {
     Red,
@@ -26,16 +174,28 @@ enum Node<T: LazyParam> {
     },
     Tree {
         val: T::Element,
+        #[allow(dead_code)]
         lazy: T::Mapping,
         color: Color,
         rank: usize,
         len: usize,
         left: Rc<Node<T>>,
         right: Rc<Node<T>>,
+        // A copy of the leftmost element. Sorted searches read it off the
+        // right child to pick a side, so they descend once from the root in
+        // O(log n) rather than walking a spine at every level.
+        first: T::Element,
     },
 }
 use Node::{Leaf, Tree};
 impl<T: LazyParam> Node<T> {
+    fn alloc(node: Self) -> Rc<Self> {
+        #[cfg(any(test, feature = "alloc-count"))]
+        NODE_ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        #[cfg(any(test, feature = "metrics"))]
+        NODES_BUILT.with(|count| count.set(count.get() + 1));
+        Rc::new(node)
+    }
     fn new(color: Color, left: Rc<Node<T>>, right: Rc<Node<T>>) -> Self {
         Tree {
             val: left.val().clone() + right.val().clone(),
@@ -46,11 +206,24 @@ impl<T: LazyParam> Node<T> {
                     Black => 1,
                     Red => 0,
                 },
-            len: left.len() + right.len(),
+            len: left
+                .len()
+                .checked_add(right.len())
+                .unwrap_or_else(|| panic!("{}", SeqError::LengthOverflow)),
+            first: left.first().clone(),
             left,
             right,
         }
     }
+    // Recomputes everything derived from the children after replacing one.
+    fn refresh(&mut self) {
+        if let Tree {
+            color, left, right, ..
+        } = self
+        {
+            *self = Self::new(*color, Rc::clone(left), Rc::clone(right));
+        }
+    }
     fn val(&self) -> &T::Element {
         match self {
             Leaf { val } => val,
@@ -75,6 +248,12 @@ impl<T: LazyParam> Node<T> {
             Tree { len, .. } => *len,
         }
     }
+    fn first(&self) -> &T::Element {
+        match self {
+            Leaf { val } => val,
+            Tree { first, .. } => first,
+        }
+    }
     fn left(&self) -> &Rc<Node<T>> {
         match self {
             Leaf { .. } => unreachable!(),
@@ -99,9 +278,278 @@ impl<T: LazyParam> Node<T> {
             }
         }
     }
+    // Returns the offset and leaf of the leftmost minimum (or maximum) of
+    // `[l, r)`: picks the best covering subtree by its cached fold, then
+    // follows the child holding that extremum down to a leaf.
+    fn extremum_range(
+        node: &Rc<Self>,
+        l: usize,
+        r: usize,
+        is_max: bool,
+    ) -> Option<(usize, &Rc<Self>)>
+    where
+        T::Element: Extrema,
+    {
+        let mut nodes = vec![];
+        if l < r {
+            Self::decompose(node, l, r, &mut nodes);
+        }
+        let mut offset = l;
+        let mut best: Option<(usize, &Rc<Self>)> = None;
+        for node in nodes {
+            if best.is_none_or(|(_, best)| Self::beats(node, best, is_max)) {
+                best = Some((offset, node));
+            }
+            offset += node.len();
+        }
+        let (mut index, mut node) = best?;
+        while let Tree { left, right, .. } = node.as_ref() {
+            if Self::beats(right, left, is_max) {
+                index += left.len();
+                node = right;
+            } else {
+                node = left;
+            }
+        }
+        Some((index, node))
+    }
+    // Whether the extremum folded into `a` is strictly better than that of `b`.
+    fn beats(a: &Self, b: &Self, is_max: bool) -> bool
+    where
+        T::Element: Extrema,
+    {
+        if is_max {
+            a.val().max_key() > b.val().max_key()
+        } else {
+            a.val().min_key() < b.val().min_key()
+        }
+    }
+    fn partition_point(&self, pred: &impl Fn(&T::Element) -> bool) -> usize {
+        match self {
+            Leaf { val } => pred(val) as usize,
+            Tree { left, right, .. } => {
+                if pred(right.first()) {
+                    left.len() + right.partition_point(pred)
+                } else {
+                    left.partition_point(pred)
+                }
+            }
+        }
+    }
+    fn max_right(
+        &self,
+        acc: T::Element,
+        pred: &impl Fn(&T::Element) -> bool,
+    ) -> (usize, T::Element) {
+        let all = acc.clone() + self.val().clone();
+        if pred(&all) {
+            return (self.len(), all);
+        }
+        match self {
+            Leaf { .. } => (0, acc),
+            Tree { left, right, .. } => {
+                let (index, acc) = left.max_right(acc, pred);
+                if index < left.len() {
+                    (index, acc)
+                } else {
+                    let (index, acc) = right.max_right(acc, pred);
+                    (left.len() + index, acc)
+                }
+            }
+        }
+    }
+    fn map_in_place(node: &mut Rc<Self>, f: &impl Fn(&mut T::Element)) {
+        if let Some(inner) = Rc::get_mut(node) {
+            match inner {
+                Leaf { val } => f(val),
+                Tree {
+                    val,
+                    left,
+                    right,
+                    first,
+                    ..
+                } => {
+                    Self::map_in_place(left, f);
+                    Self::map_in_place(right, f);
+                    *val = left.val().clone() + right.val().clone();
+                    *first = left.first().clone();
+                }
+            }
+        } else {
+            *node = Self::alloc(match node.as_ref() {
+                Leaf { val } => {
+                    let mut val = val.clone();
+                    f(&mut val);
+                    Leaf { val }
+                }
+                Tree {
+                    color, left, right, ..
+                } => {
+                    let (mut left, mut right) = (Rc::clone(left), Rc::clone(right));
+                    Self::map_in_place(&mut left, f);
+                    Self::map_in_place(&mut right, f);
+                    Self::new(*color, left, right)
+                }
+            });
+        }
+    }
+    // Calls `f` with the index and value of every element of `[l, r)`, where
+    // this node starts at `offset`.
+    fn visit_range(
+        &self,
+        offset: usize,
+        l: usize,
+        r: usize,
+        f: &mut impl FnMut(usize, &T::Element),
+    ) {
+        match self {
+            Leaf { val } => f(offset, val),
+            Tree { left, right, .. } => {
+                let mid = offset + left.len();
+                if l < mid {
+                    left.visit_range(offset, l, r.min(mid), f);
+                }
+                if mid < r {
+                    right.visit_range(mid, l.max(mid), r, f);
+                }
+            }
+        }
+    }
+    // Collects the maximal subtrees covering `[l, r)`, left to right.
+    fn decompose<'a>(node: &'a Rc<Self>, l: usize, r: usize, out: &mut Vec<&'a Rc<Self>>) {
+        #[cfg(test)]
+        NODE_VISITS.with(|count| count.set(count.get() + 1));
+        if l == 0 && r == node.len() {
+            out.push(node);
+            return;
+        }
+        let (left, right) = (node.left(), node.right());
+        if l < left.len() {
+            Self::decompose(left, l, r.min(left.len()), out);
+        }
+        if left.len() < r {
+            Self::decompose(right, l.max(left.len()) - left.len(), r - left.len(), out);
+        }
+    }
+    // Adds this node's fold to every query in `active` that covers it and
+    // hands the rest down to the children they overlap. `node` spans
+    // `offset..offset + len` and every query in `active` overlaps it; children
+    // are walked left to right, so each query sees its pieces in order.
+    fn fold_batch(
+        node: &Rc<Self>,
+        offset: usize,
+        ranges: &[(usize, usize)],
+        active: &[usize],
+        out: &mut [T::Element],
+    ) {
+        #[cfg(test)]
+        NODE_VISITS.with(|count| count.set(count.get() + 1));
+        let end = offset + node.len();
+        let mid = match node.as_ref() {
+            Leaf { .. } => end,
+            Tree { left, .. } => offset + left.len(),
+        };
+        let (mut to_left, mut to_right) = (vec![], vec![]);
+        for &q in active {
+            let (l, r) = ranges[q];
+            if l <= offset && end <= r {
+                let acc = mem::replace(&mut out[q], T::Element::zero());
+                out[q] = acc + node.val().clone();
+            } else {
+                if l < mid {
+                    to_left.push(q);
+                }
+                if mid < r {
+                    to_right.push(q);
+                }
+            }
+        }
+        if let Tree { left, right, .. } = node.as_ref() {
+            if !to_left.is_empty() {
+                Self::fold_batch(left, offset, ranges, &to_left, out);
+            }
+            if !to_right.is_empty() {
+                Self::fold_batch(right, mid, ranges, &to_right, out);
+            }
+        }
+    }
+    // Whether `pred` holds for some element of `[l, r)`, stopping at the
+    // first one found.
+    fn any_in_range(&self, l: usize, r: usize, pred: &impl Fn(&T::Element) -> bool) -> bool {
+        match self {
+            Leaf { val } => pred(val),
+            Tree { left, right, .. } => {
+                let mid = left.len();
+                l < mid && left.any_in_range(l, r.min(mid), pred)
+                    || mid < r && right.any_in_range(l.max(mid) - mid, r - mid, pred)
+            }
+        }
+    }
+    fn set(node: &Rc<Self>, index: usize, val: T::Element) -> Rc<Self> {
+        match node.as_ref() {
+            Leaf { .. } => Self::alloc(Leaf { val }),
+            Tree {
+                color, left, right, ..
+            } => Self::alloc(if index < left.len() {
+                Self::new(*color, Self::set(left, index, val), Rc::clone(right))
+            } else {
+                Self::new(
+                    *color,
+                    Rc::clone(left),
+                    Self::set(right, index - left.len(), val),
+                )
+            }),
+        }
+    }
+    // Whether this node's cached rank and length agree with its children and
+    // it isn't a red node with a red child. Since ranks are cached, this
+    // holding at every node makes all black heights equal.
+    fn is_consistent(&self) -> bool {
+        match self {
+            Leaf { .. } => true,
+            Tree {
+                color,
+                rank,
+                len,
+                left,
+                right,
+                ..
+            } => {
+                let black = |node: &Self| matches!(node.color(), Black) as usize;
+                left.rank() + black(left) == *rank
+                    && right.rank() + black(right) == *rank
+                    && left.len() + right.len() == *len
+                    && (matches!(color, Black) || black(left) + black(right) == 2)
+            }
+        }
+    }
+    fn check(&self) -> bool {
+        self.is_consistent()
+            && match self {
+                Leaf { .. } => true,
+                Tree { left, right, .. } => left.check() && right.check(),
+            }
+    }
+    // Checks only the nodes on the path to `index`, which is where `merge`
+    // works, in O(log n).
+    fn check_path(&self, mut index: usize) -> bool {
+        let mut node = self;
+        while let Tree { left, right, .. } = node {
+            if !node.is_consistent() {
+                return false;
+            }
+            if index < left.len() {
+                node = left;
+            } else {
+                index -= left.len();
+                node = right;
+            }
+        }
+        true
+    }
     fn to_black(src: &Rc<Self>) -> Rc<Self> {
         match src.color() {
-            Red => Rc::new(Self::new(
+            Red => Self::alloc(Self::new(
                 Black,
                 Rc::clone(src.left()),
                 Rc::clone(src.right()),
@@ -109,78 +557,165 @@ impl<T: LazyParam> Node<T> {
             Black => Rc::clone(src),
         }
     }
-    fn merge(left: &Rc<Self>, right: &Rc<Self>) -> Rc<Self> {
-        Rc::new(if left.rank() < right.rank() {
-            let left = &Node::merge(left, right.left());
+    fn into_black(mut self) -> Self {
+        if let Tree { color, .. } = &mut self {
+            *color = Black;
+        }
+        self
+    }
+    fn merge_black(left: &Rc<Self>, right: &Rc<Self>) -> Rc<Self> {
+        Self::alloc(Self::merge(left, right).into_black())
+    }
+    // Like `to_black`, but recolors in place when `node` is not shared.
+    fn owned_to_black(mut node: Rc<Self>) -> Rc<Self> {
+        match Rc::get_mut(&mut node) {
+            Some(Tree { color, .. }) => *color = Black,
+            Some(Leaf { .. }) => {}
+            None => return Self::to_black(&node),
+        }
+        node
+    }
+    fn merge_black_owned(left: Rc<Self>, right: Rc<Self>) -> Rc<Self> {
+        Self::owned_to_black(Self::merge_owned(left, right))
+    }
+    // Fixes up `merged`, a red node with a red left child that is to become
+    // the left child of a black node whose right child is `right_right`.
+    fn lift_left(merged: &Self, right_right: &Rc<Self>) -> Self {
+        match right_right.color() {
+            Black => Self::new(
+                Black,
+                Rc::clone(merged.left()),
+                Self::alloc(Self::new(
+                    Red,
+                    Rc::clone(merged.right()),
+                    Rc::clone(right_right),
+                )),
+            ),
+            Red => Self::new(
+                Red,
+                Self::alloc(Self::new(
+                    Black,
+                    Rc::clone(merged.left()),
+                    Rc::clone(merged.right()),
+                )),
+                Self::alloc(Self::new(
+                    Black,
+                    Rc::clone(right_right.left()),
+                    Rc::clone(right_right.right()),
+                )),
+            ),
+        }
+    }
+    // The mirror image of `lift_left`.
+    fn lift_right(left_left: &Rc<Self>, merged: &Self) -> Self {
+        match left_left.color() {
+            Black => Self::new(
+                Black,
+                Self::alloc(Self::new(
+                    Red,
+                    Rc::clone(left_left),
+                    Rc::clone(merged.left()),
+                )),
+                Rc::clone(merged.right()),
+            ),
+            Red => Self::new(
+                Red,
+                Self::alloc(Self::new(
+                    Black,
+                    Rc::clone(left_left.left()),
+                    Rc::clone(left_left.right()),
+                )),
+                Self::alloc(Self::new(
+                    Black,
+                    Rc::clone(merged.left()),
+                    Rc::clone(merged.right()),
+                )),
+            ),
+        }
+    }
+    // Returns the merged root unwrapped, so that callers which immediately
+    // recolor or take it apart don't pay for an intermediate allocation.
+    fn merge(left: &Rc<Self>, right: &Rc<Self>) -> Self {
+        if left.rank() < right.rank() {
+            let left = Node::merge(left, right.left());
             match (left.color(), left.left().color(), right.color()) {
-                (Red, Red, Black) => match right.right().color() {
-                    Black => Self::new(
-                        Black,
-                        Rc::clone(left.left()),
-                        Rc::new(Self::new(
-                            Red,
-                            Rc::clone(left.right()),
-                            Rc::clone(right.right()),
-                        )),
-                    ),
-                    Red => Self::new(
-                        Red,
-                        Rc::new(Self::new(
-                            Black,
-                            Rc::clone(left.left()),
-                            Rc::clone(left.right()),
-                        )),
-                        Rc::new(Self::new(
-                            Black,
-                            Rc::clone(right.right().left()),
-                            Rc::clone(right.right().right()),
-                        )),
-                    ),
-                },
-                _ => Self::new(right.color(), Rc::clone(left), Rc::clone(right.right())),
+                (Red, Red, Black) => Self::lift_left(&left, right.right()),
+                _ => Self::new(right.color(), Self::alloc(left), Rc::clone(right.right())),
             }
         } else if left.rank() > right.rank() {
-            let right = &Node::merge(left.right(), right);
+            let right = Node::merge(left.right(), right);
             match (left.color(), right.right().color(), right.color()) {
-                (Black, Red, Red) => match left.left().color() {
-                    Black => Self::new(
-                        Black,
-                        Rc::new(Self::new(
-                            Red,
-                            Rc::clone(left.left()),
-                            Rc::clone(right.left()),
-                        )),
-                        Rc::clone(right.right()),
-                    ),
-                    Red => Self::new(
-                        Red,
-                        Rc::new(Self::new(
-                            Black,
-                            Rc::clone(left.left().left()),
-                            Rc::clone(left.left().right()),
-                        )),
-                        Rc::new(Self::new(
-                            Black,
-                            Rc::clone(right.left()),
-                            Rc::clone(right.right()),
-                        )),
-                    ),
-                },
-                _ => Self::new(left.color(), Rc::clone(left.left()), Rc::clone(right)),
+                (Black, Red, Red) => Self::lift_right(left.left(), &right),
+                _ => Self::new(left.color(), Rc::clone(left.left()), Self::alloc(right)),
             }
         } else {
             Self::new(Red, Rc::clone(left), Rc::clone(right))
-        })
+        }
+    }
+    // Like `merge`, but reuses the nodes along the merged spine in place when
+    // nothing else refers to them.
+    fn merge_owned(mut left: Rc<Self>, mut right: Rc<Self>) -> Rc<Self> {
+        if left.rank() < right.rank() {
+            let Some(Tree {
+                left: right_left,
+                right: right_right,
+                ..
+            }) = Rc::get_mut(&mut right)
+            else {
+                return Self::alloc(Self::merge(&left, &right));
+            };
+            let right_left = mem::replace(right_left, Rc::clone(right_right));
+            let merged = Self::merge_owned(left, right_left);
+            if let (Red, Red, Black) = (merged.color(), merged.left().color(), right.color()) {
+                return Self::alloc(Self::lift_left(&merged, right.right()));
+            }
+            let node = Rc::get_mut(&mut right).unwrap();
+            if let Tree { left, .. } = node {
+                *left = merged;
+            }
+            node.refresh();
+            right
+        } else if left.rank() > right.rank() {
+            let Some(Tree {
+                left: left_left,
+                right: left_right,
+                ..
+            }) = Rc::get_mut(&mut left)
+            else {
+                return Self::alloc(Self::merge(&left, &right));
+            };
+            let left_right = mem::replace(left_right, Rc::clone(left_left));
+            let merged = Self::merge_owned(left_right, right);
+            if let (Black, Red, Red) = (left.color(), merged.right().color(), merged.color()) {
+                return Self::alloc(Self::lift_right(left.left(), &merged));
+            }
+            let node = Rc::get_mut(&mut left).unwrap();
+            if let Tree { right, .. } = node {
+                *right = merged;
+            }
+            node.refresh();
+            left
+        } else {
+            Self::alloc(Self::new(Red, left, right))
+        }
     }
     fn split(tree: &Rc<Self>, index: usize) -> (Rc<Self>, Rc<Self>) {
         match tree.as_ref() {
             Tree { left, right, .. } => {
                 if index < left.len() {
+                    // `merge` compares ranks, which only measure height
+                    // between black roots, so the sibling is blackened first.
                     let (left_left, left_right) = Self::split(left, index);
-                    (left_left, Self::to_black(&Self::merge(&left_right, right)))
+                    (
+                        left_left,
+                        Self::merge_black(&left_right, &Self::to_black(right)),
+                    )
                 } else if index > left.len() {
                     let (right_left, right_right) = Self::split(right, index - left.len());
-                    (Self::to_black(&Self::merge(left, &right_left)), right_right)
+                    (
+                        Self::merge_black(&Self::to_black(left), &right_left),
+                        right_right,
+                    )
                 } else {
                     (Self::to_black(left), Self::to_black(right))
                 }
@@ -188,13 +723,196 @@ impl<T: LazyParam> Node<T> {
             _ => unreachable!(),
         }
     }
+    // Like `split`, but takes apart the nodes on the path that are not shared
+    // instead of copying them.
+    fn split_owned(tree: Rc<Self>, index: usize) -> (Rc<Self>, Rc<Self>) {
+        let (left, right) = match Rc::try_unwrap(tree) {
+            Ok(Tree { left, right, .. }) => (left, right),
+            Ok(Leaf { .. }) => unreachable!(),
+            Err(tree) => return Self::split(&tree, index),
+        };
+        if index < left.len() {
+            let (left_left, left_right) = Self::split_owned(left, index);
+            (
+                left_left,
+                Self::merge_black_owned(left_right, Self::owned_to_black(right)),
+            )
+        } else if index > left.len() {
+            let index = index - left.len();
+            let (right_left, right_right) = Self::split_owned(right, index);
+            (
+                Self::merge_black_owned(Self::owned_to_black(left), right_left),
+                right_right,
+            )
+        } else {
+            (Self::owned_to_black(left), Self::owned_to_black(right))
+        }
+    }
+}
+
+/// A version frozen for history or undo, as returned by `snapshot`.
+///
+/// It reads like the tree it wraps, but marks at the type level that it is
+/// kept rather than edited. Edits to the working tree never affect it.
+pub struct Snapshot<T: LazyParam>(PersistentLazyRBTree<T>);
+impl<T: LazyParam> Snapshot<T> {
+    /// Returns the tree, to continue editing from this version.
+    pub fn into_inner(self) -> PersistentLazyRBTree<T> {
+        self.0
+    }
+}
+impl<T: LazyParam> Clone for Snapshot<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+impl<T: LazyParam> Deref for Snapshot<T> {
+    type Target = PersistentLazyRBTree<T>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Summary statistics of a non-empty sequence, as returned by `stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats<E> {
+    pub min: E,
+    pub max: E,
+    /// The fold of the whole sequence.
+    pub sum: E,
+    pub count: usize,
+}
+
+/// The error returned by the fallible `try_*` operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqError {
+    OutOfBounds {
+        index: usize,
+        len: usize,
+    },
+    InvalidRange {
+        l: usize,
+        r: usize,
+        len: usize,
+    },
+    /// The result would hold more than `usize::MAX` elements.
+    LengthOverflow,
+}
+impl fmt::Display for SeqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { index, len } => write!(
+                f,
+                "index {} out of bounds for sequence of length {}",
+                index, len
+            ),
+            Self::InvalidRange { l, r, len } => write!(
+                f,
+                "range {}..{} invalid for sequence of length {}",
+                l, r, len
+            ),
+            Self::LengthOverflow => write!(f, "sequence length overflow"),
+        }
+    }
+}
+impl Error for SeqError {}
+
+/// An edit for `PersistentLazyRBTree::apply`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op<E> {
+    Insert(usize, E),
+    Erase(usize),
+    Set(usize, E),
+}
+
+// The remaining sequence from some index on, as a stack of subtrees whose top
+// is the leftmost, or the rightmost when walking in reverse. Descending lazily
+// lets two cursors skip shared subtrees. Every in-order walk in the crate goes
+// through this, so a full walk costs O(n) rather than O(n log n) lookups.
+struct InOrder<'a, T: LazyParam> {
+    stack: Vec<&'a Rc<Node<T>>>,
+    rev: bool,
+}
+impl<'a, T: LazyParam> InOrder<'a, T> {
+    fn new(root: Option<&'a Rc<Node<T>>>, mut index: usize) -> Self {
+        let mut stack = vec![];
+        let mut node = match root {
+            Some(root) if index < root.len() => root,
+            _ => return Self { stack, rev: false },
+        };
+        while index > 0 {
+            let (left, right) = (node.left(), node.right());
+            if index < left.len() {
+                stack.push(right);
+                node = left;
+            } else {
+                index -= left.len();
+                node = right;
+            }
+        }
+        stack.push(node);
+        Self { stack, rev: false }
+    }
+    // The sequence before `end`, from its last element backwards.
+    fn new_rev(root: Option<&'a Rc<Node<T>>>, mut end: usize) -> Self {
+        let mut stack = vec![];
+        let mut node = match root {
+            Some(root) if end > 0 => root,
+            _ => return Self { stack, rev: true },
+        };
+        while end < node.len() {
+            let (left, right) = (node.left(), node.right());
+            if end <= left.len() {
+                node = left;
+            } else {
+                stack.push(left);
+                end -= left.len();
+                node = right;
+            }
+        }
+        stack.push(node);
+        Self { stack, rev: true }
+    }
+    fn peek(&self) -> Option<&'a Rc<Node<T>>> {
+        self.stack.last().copied()
+    }
+    fn pop(&mut self) {
+        self.stack.pop();
+    }
+    fn descend(&mut self) {
+        let node = self.stack.pop().unwrap();
+        if self.rev {
+            self.stack.push(node.left());
+            self.stack.push(node.right());
+        } else {
+            self.stack.push(node.right());
+            self.stack.push(node.left());
+        }
+    }
+}
+impl<'a, T: LazyParam> Iterator for InOrder<'a, T> {
+    type Item = &'a T::Element;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.peek()?;
+            #[cfg(test)]
+            NODE_VISITS.with(|count| count.set(count.get() + 1));
+            match node.as_ref() {
+                Leaf { val } => {
+                    self.pop();
+                    return Some(val);
+                }
+                Tree { .. } => self.descend(),
+            }
+        }
+    }
 }
 
 pub struct PersistentLazyRBTree<T: LazyParam> {
     root: Option<Rc<Node<T>>>,
 }
 impl<T: LazyParam> PersistentLazyRBTree<T> {
-    fn from(root: Rc<Node<T>>) -> Self {
+    fn from_root(root: Rc<Node<T>>) -> Self {
         Self { root: Some(root) }
     }
     pub fn new() -> Self {
@@ -203,104 +921,1170 @@ impl<T: LazyParam> PersistentLazyRBTree<T> {
     pub fn len(&self) -> usize {
         self.root.as_ref().map_or(0, |root| root.len())
     }
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+    /// Freezes this version in O(1), sharing all nodes.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot(self.clone())
+    }
+    /// The black height of the root, or 0 for an empty tree.
+    #[doc(hidden)]
+    pub fn root_rank(&self) -> usize {
+        self.root.as_ref().map_or(0, |root| root.rank())
+    }
+    /// Whether the root is black; an empty tree counts as black.
+    #[doc(hidden)]
+    pub fn root_is_black(&self) -> bool {
+        self.root
+            .as_ref()
+            .is_none_or(|root| matches!(root.color(), Black))
+    }
+    /// The length `merge(a, b)` would have, without building it.
+    pub fn merged_len(a: &Self, b: &Self) -> usize {
+        a.len() + b.len()
+    }
+    /// The root ranks of `a` and `b`. Merging costs O(difference + 1), so
+    /// planners can use this to pick a cheap merge order.
+    pub fn would_balance(a: &Self, b: &Self) -> (usize, usize) {
+        (a.root_rank(), b.root_rank())
+    }
+    /// Whether the root is black, no red node has a red child, every
+    /// root-to-leaf path has the same number of black nodes, and cached ranks
+    /// and lengths are consistent. Takes O(n).
+    pub fn check_invariants(&self) -> bool {
+        self.root_is_black() && self.root.as_ref().is_none_or(|root| root.check())
+    }
+    // Like `check_invariants`, but only along the path to `index`.
+    fn check_path(&self, index: usize) -> bool {
+        self.root_is_black()
+            && self
+                .root
+                .as_ref()
+                .is_none_or(|root| root.check_path(index.min(root.len() - 1)))
+    }
+    /// In debug builds, asserts that the invariants hold along the paths the
+    /// merge walks in both operands and in the result.
     pub fn merge(left: &Self, right: &Self) -> Self {
-        match (&left.root, &right.root) {
+        debug_assert!(left.check_path(left.len().saturating_sub(1)) && right.check_path(0));
+        let merged = match (&left.root, &right.root) {
             (None, _) => right.clone(),
             (_, None) => left.clone(),
-            (Some(left), Some(right)) => Self::from(Node::to_black(&Node::merge(left, right))),
+            (Some(left), Some(right)) => {
+                Self::from_root(record(OpKind::Merge, || Node::merge_black(left, right)))
+            }
+        };
+        debug_assert!(
+            merged.check_path(left.len().saturating_sub(1)) && merged.check_path(left.len())
+        );
+        merged
+    }
+    /// Like `merge`, but returns an error when the result would be too long
+    /// to index.
+    pub fn try_merge(left: &Self, right: &Self) -> Result<Self, SeqError> {
+        match left.len().checked_add(right.len()) {
+            Some(_) => Ok(Self::merge(left, right)),
+            None => Err(SeqError::LengthOverflow),
+        }
+    }
+    // Merges adjacent pairs round by round, so that no single merge joins
+    // trees of wildly different ranks.
+    fn merge_all(mut trees: Vec<Self>) -> Self {
+        while trees.len() > 1 {
+            trees = trees
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Self::merge(left, right),
+                    [single] => single.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        trees.pop().unwrap_or_default()
+    }
+    /// Concatenates `parts` with `sep` between each adjacent pair.
+    pub fn join(parts: &[Self], sep: &Self) -> Self {
+        let mut trees = Vec::with_capacity(2 * parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                trees.push(sep.clone());
+            }
+            trees.push(part.clone());
+        }
+        Self::merge_all(trees)
+    }
+    /// Like `merge`, but takes ownership so that nodes not shared with other
+    /// versions can be reused instead of copied.
+    pub fn merge_owned(left: Self, right: Self) -> Self {
+        match (left.root, right.root) {
+            (None, root) | (root, None) => Self { root },
+            (Some(left), Some(right)) => Self::from_root(record(OpKind::Merge, || {
+                Node::merge_black_owned(left, right)
+            })),
+        }
+    }
+    /// Joins two adjacent halves, such as those of `split_take`. The same as
+    /// `merge_owned`.
+    pub fn from_parts(left: Self, right: Self) -> Self {
+        Self::merge_owned(left, right)
+    }
+    /// Like `split`, but consumes the tree so that nodes on the split path
+    /// that nothing else shares are reused rather than copied.
+    pub fn split_take(self, index: usize) -> (Self, Self) {
+        let len = self.len();
+        match self.root {
+            _ if index > len => panic!("{}", SeqError::OutOfBounds { index, len }),
+            Some(root) if 0 < index && index < len => {
+                let (left, right) = record(OpKind::Split, || Node::split_owned(root, index));
+                (Self::from_root(left), Self::from_root(right))
+            }
+            root if index == 0 => (Self::new(), Self { root }),
+            root => (Self { root }, Self::new()),
         }
     }
     pub fn split(&self, index: usize) -> (Self, Self) {
-        assert!(index <= self.len());
-        if index == 0 {
-            (Self::new(), self.clone())
+        self.try_split(index)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+    pub fn try_split(&self, index: usize) -> Result<(Self, Self), SeqError> {
+        if index > self.len() {
+            Err(SeqError::OutOfBounds {
+                index,
+                len: self.len(),
+            })
+        } else if index == 0 {
+            Ok((Self::new(), self.clone()))
         } else if index == self.len() {
-            (self.clone(), Self::new())
+            Ok((self.clone(), Self::new()))
         } else {
-            let (left, right) = Node::split(self.root.as_ref().unwrap(), index);
-            (Self::from(left), Self::from(right))
+            let root = self.root.as_ref().unwrap();
+            let (left, right) = record(OpKind::Split, || Node::split(root, index));
+            Ok((Self::from_root(left), Self::from_root(right)))
         }
     }
-    pub fn insert(&self, index: usize, val: T::Element) -> Self {
-        assert!(index <= self.len());
-        let (ref left, ref right) = self.split(index);
+    /// Splits at every index of `indices`, which must be strictly increasing
+    /// and at most `len()`, returning the `indices.len() + 1` segments.
+    pub fn split_at_many(&self, indices: &[usize]) -> Vec<Self> {
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        assert!(indices.last().is_none_or(|&last| last <= self.len()));
+        let mut res = Vec::with_capacity(indices.len() + 1);
+        let mut rest = self.clone();
+        let mut offset = 0;
+        for &index in indices {
+            let (left, right) = rest.split(index - offset);
+            res.push(left);
+            rest = right;
+            offset = index;
+        }
+        res.push(rest);
+        res
+    }
+    /// Returns the elements within `range`, accepting any of the `..` forms.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Self {
+        let out_of_bounds = || -> usize {
+            panic!(
+                "slice range out of bounds for sequence of length {}",
+                self.len()
+            )
+        };
+        let l = match range.start_bound() {
+            Bound::Included(&l) => l,
+            Bound::Excluded(&l) => l.checked_add(1).unwrap_or_else(out_of_bounds),
+            Bound::Unbounded => 0,
+        };
+        let r = match range.end_bound() {
+            Bound::Included(&r) => r.checked_add(1).unwrap_or_else(out_of_bounds),
+            Bound::Excluded(&r) => r,
+            Bound::Unbounded => self.len(),
+        };
+        if l > r || r > self.len() {
+            out_of_bounds();
+        }
+        self.split(r).0.split(l).1
+    }
+    /// Like `slice(l..r)`, but clamps both bounds to `len()` and swaps them
+    /// if `l > r` instead of panicking.
+    pub fn subrange_clamped(&self, l: usize, r: usize) -> Self {
+        let (l, r) = (l.min(self.len()), r.min(self.len()));
+        self.slice(l.min(r)..l.max(r))
+    }
+    /// Whether `a` and `b` split out to the very same subtree, as happens for
+    /// aligned copies of a block merged with itself. A `false` says nothing
+    /// about the contents.
+    pub fn range_shared(&self, a: Range<usize>, b: Range<usize>) -> bool {
+        match (self.slice(a).root, self.slice(b).root) {
+            (Some(a), Some(b)) => Rc::ptr_eq(&a, &b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+    /// Returns the first `min(n, len())` elements.
+    pub fn take(&self, n: usize) -> Self {
+        self.split(n.min(self.len())).0
+    }
+    /// Returns all but the first `min(n, len())` elements.
+    pub fn skip(&self, n: usize) -> Self {
+        self.split(n.min(self.len())).1
+    }
+    /// Pairs up elements of `self` and `other`, dropping the excess of the
+    /// longer one like `Iterator::zip`.
+    pub fn zip<U: LazyParam>(
+        &self,
+        other: &PersistentLazyRBTree<U>,
+    ) -> PersistentLazyRBTree<(T, U)> {
+        self.iter()
+            .zip(other.iter())
+            .map(|(a, b)| Pair(a.clone(), b.clone()))
+            .collect()
+    }
+    /// Alternates elements of `a` and `b`, starting with `a`, then appends the
+    /// rest of the longer one.
+    pub fn interleave(a: &Self, b: &Self) -> Self {
+        let (mut a, mut b) = (a.iter(), b.iter());
+        let mut from_a = true;
+        std::iter::from_fn(|| {
+            from_a = !from_a;
+            if from_a {
+                b.next().or_else(|| a.next())
+            } else {
+                a.next().or_else(|| b.next())
+            }
+        })
+        .cloned()
+        .collect()
+    }
+    /// Inserts `val` after every element not greater than it, assuming the
+    /// sequence is sorted by `cmp`. Finds the position in one O(log n)
+    /// descent, comparing against the leftmost element of each right child.
+    pub fn insert_by(
+        &self,
+        val: T::Element,
+        cmp: impl Fn(&T::Element, &T::Element) -> Ordering,
+    ) -> Self {
+        let index = self.partition_point(|x| cmp(x, &val) != Ordering::Greater);
+        self.insert(index, val)
+    }
+    fn partition_point(&self, pred: impl Fn(&T::Element) -> bool) -> usize {
+        self.root
+            .as_ref()
+            .map_or(0, |root| root.partition_point(&pred))
+    }
+    /// Replaces `[l, r)` with the items of `replacement`.
+    pub fn splice_iter<I: IntoIterator<Item = T::Element>>(
+        &self,
+        l: usize,
+        r: usize,
+        replacement: I,
+    ) -> Self {
+        assert!(l <= r && r <= self.len());
+        let (ref left, ref right) = self.split(r);
+        let (ref left, _) = left.split(l);
         Self::merge(
-            &Self::merge(left, &Self::from(Rc::new(Leaf { val }))),
+            &Self::merge(left, &replacement.into_iter().collect()),
             right,
         )
     }
-    pub fn erase(&self, index: usize) -> Self {
-        assert!(index < self.len());
-        let (ref left, ref right) = self.split(index);
-        let (_, ref right) = right.split(1);
-        Self::merge(left, right)
+    /// Drops the leading run of elements satisfying `pred`. Only that run is
+    /// visited, followed by a single split.
+    pub fn trim_start(&self, pred: impl Fn(&T::Element) -> bool) -> Self {
+        let n = self.iter().position(|x| !pred(x)).unwrap_or(self.len());
+        self.skip(n)
     }
-    pub fn fold(&self, range: Range<usize>) -> T::Element {
-        let (left, _) = self.split(range.end);
-        let (_, mid) = left.split(range.start);
-        mid.root
-            .map(|root| root.val().clone())
-            .unwrap_or(T::Element::zero())
+    /// Drops the trailing run of elements satisfying `pred`.
+    pub fn trim_end(&self, pred: impl Fn(&T::Element) -> bool) -> Self {
+        let n = self.rev().position(|x| !pred(x)).unwrap_or(self.len());
+        self.take(self.len() - n)
     }
-    pub fn iter(&self) -> Iter<T> {
-        Iter {
-            begin: 0,
-            end: self.len(),
-            tree: self,
-        }
+    /// Replaces the `src.len()` elements from `start` on with those of `src`,
+    /// keeping the length unchanged.
+    pub fn overwrite_range(&self, start: usize, src: &Self) -> Self {
+        assert!(start
+            .checked_add(src.len())
+            .is_some_and(|end| end <= self.len()));
+        let (ref left, ref right) = self.split(start);
+        let right = &right.skip(src.len());
+        Self::merge(&Self::merge(left, src), right)
     }
-}
-impl<T: LazyParam> Clone for PersistentLazyRBTree<T> {
-    fn clone(&self) -> Self {
-        Self {
-            root: self.root.clone(),
+    /// Overwrites consecutive elements from `start` on with `items`, keeping
+    /// the length unchanged. Items past the end of the sequence are ignored.
+    pub fn set_range_iter<I: IntoIterator<Item = T::Element>>(
+        &self,
+        start: usize,
+        items: I,
+    ) -> Self {
+        assert!(start <= self.len());
+        let src = items.into_iter().take(self.len() - start).collect();
+        self.overwrite_range(start, &src)
+    }
+    /// Applies `ops` in order, each index referring to the sequence as left by
+    /// the previous ones, and returns the final version.
+    pub fn apply(&self, ops: &[Op<T::Element>]) -> Self {
+        ops.iter().fold(self.clone(), |tree, op| match op {
+            Op::Insert(index, val) => tree.insert(*index, val.clone()),
+            Op::Erase(index) => tree.erase(*index),
+            Op::Set(index, val) => tree.set(*index, val.clone()),
+        })
+    }
+    /// Inserts every `(index, val)` before the element at `index` of the
+    /// original sequence, rebuilding it once. Items with equal indices keep
+    /// their order in `inserts`.
+    pub fn scatter_insert(&self, inserts: &[(usize, T::Element)]) -> Self {
+        let mut inserts: Vec<_> = inserts.iter().collect();
+        inserts.sort_by_key(|(index, _)| *index);
+        assert!(inserts.last().is_none_or(|(index, _)| *index <= self.len()));
+        let mut inserts = inserts.into_iter().peekable();
+        let mut items = Vec::with_capacity(self.len() + inserts.len());
+        for (i, x) in self.into_cloned_iter().map(Some).chain([None]).enumerate() {
+            while let Some((_, val)) = inserts.next_if(|(index, _)| *index == i) {
+                items.push(val.clone());
+            }
+            items.extend(x);
         }
+        Self::from_exact_iter(items)
     }
-}
-impl<T: LazyParam> Index<usize> for PersistentLazyRBTree<T> {
-    type Output = T::Element;
-    fn index(&self, index: usize) -> &Self::Output {
-        assert!(index < self.len());
-        self.root.as_ref().unwrap().index(index)
+    /// Rotates `[l, r)` so that the element at `mid` comes first, like
+    /// `slice::rotate_left` on that window.
+    pub fn rotate_range(&self, l: usize, r: usize, mid: usize) -> Self {
+        assert!(l <= mid && mid < r && r <= self.len());
+        let (ref left, ref right) = self.split(r);
+        let (ref left, ref tail) = left.split(mid);
+        let (ref left, ref head) = left.split(l);
+        Self::merge(&Self::merge(left, &Self::merge(tail, head)), right)
     }
-}
-pub struct Iter<'a, T: LazyParam> {
-    begin: usize,
-    end: usize,
-    tree: &'a PersistentLazyRBTree<T>,
-}
-impl<'a, T: LazyParam> Iterator for Iter<'a, T> {
-    type Item = &'a T::Element;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.begin < self.tree.len() {
-            let ret = Some(&self.tree[self.begin]);
-            self.begin += 1;
-            ret
-        } else {
-            None
+    pub fn insert(&self, index: usize, val: T::Element) -> Self {
+        self.try_insert(index, val)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+    pub fn try_insert(&self, index: usize, val: T::Element) -> Result<Self, SeqError> {
+        if self.len() == usize::MAX {
+            return Err(SeqError::LengthOverflow);
+        }
+        if index > self.len() {
+            return Err(SeqError::OutOfBounds {
+                index,
+                len: self.len(),
+            });
         }
+        // Owned merges move the wrappers through, so an empty side costs no
+        // clone of the other.
+        let (left, right) = self.split(index);
+        let leaf = Self::from_root(Node::alloc(Leaf { val }));
+        Ok(Self::merge_owned(Self::merge_owned(left, leaf), right))
     }
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.tree.len(), Some(self.tree.len()))
+    /// Inserts at `index` clamped to `len()`, returning the new version and
+    /// the index the element landed at.
+    pub fn insert_tracked(&self, index: usize, val: T::Element) -> (Self, usize) {
+        let index = index.min(self.len());
+        (self.insert(index, val), index)
     }
-}
-impl<'a, T: LazyParam> ExactSizeIterator for Iter<'a, T> {
-    fn len(&self) -> usize {
-        self.tree.len()
+    /// Appends `val`, returning the new version and its index, the old
+    /// `len()`.
+    pub fn push_tracked(&self, val: T::Element) -> (Self, usize) {
+        self.insert_tracked(self.len(), val)
     }
-}
-impl<'a, T: LazyParam> DoubleEndedIterator for Iter<'a, T> {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        if self.end > 0 {
-            self.end -= 1;
-            Some(&self.tree[self.end])
-        } else {
-            None
+    /// Splits the sequence into maximal runs of consecutive elements with
+    /// equal keys, each paired with its key.
+    pub fn group_by<K: Eq>(&self, key: impl Fn(&T::Element) -> K) -> Vec<(K, Self)> {
+        let mut keys: Vec<K> = vec![];
+        let mut bounds = vec![];
+        for (i, x) in self.iter().enumerate() {
+            let k = key(x);
+            if keys.last() != Some(&k) {
+                if i > 0 {
+                    bounds.push(i);
+                }
+                keys.push(k);
+            }
         }
+        keys.into_iter().zip(self.split_at_many(&bounds)).collect()
     }
-}
-impl<'a, T: LazyParam> FusedIterator for Iter<'a, T> {}
-impl<T: LazyParam> FromIterator<T::Element> for PersistentLazyRBTree<T> {
+    /// Erases the first element equal to `x`, or returns `None` if there is none.
+    pub fn remove_first(&self, x: &T::Element) -> Option<Self>
+    where
+        T::Element: PartialEq,
+    {
+        self.iter().position(|y| y == x).map(|i| self.erase(i))
+    }
+    /// Replaces the element at `index`, copying only the path to it.
+    pub fn set(&self, index: usize, val: T::Element) -> Self {
+        match &self.root {
+            Some(root) if index < root.len() => Self::from_root(Node::set(root, index, val)),
+            _ => panic!(
+                "{}",
+                SeqError::OutOfBounds {
+                    index,
+                    len: self.len(),
+                }
+            ),
+        }
+    }
+    pub fn erase(&self, index: usize) -> Self {
+        self.try_erase(index)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+    pub fn try_erase(&self, index: usize) -> Result<Self, SeqError> {
+        if index >= self.len() {
+            return Err(SeqError::OutOfBounds {
+                index,
+                len: self.len(),
+            });
+        }
+        let (left, right) = self.split(index);
+        let (_, right) = right.split(1);
+        Ok(Self::merge_owned(left, right))
+    }
+    /// Like `fold`, but returns an error instead of panicking when `range` is
+    /// out of bounds. An empty valid range folds to `Ok(zero())`.
+    pub fn try_fold(&self, range: Range<usize>) -> Result<T::Element, SeqError> {
+        if range.start <= range.end && range.end <= self.len() {
+            Ok(self.fold(range))
+        } else {
+            Err(SeqError::InvalidRange {
+                l: range.start,
+                r: range.end,
+                len: self.len(),
+            })
+        }
+    }
+    pub fn fold(&self, range: Range<usize>) -> T::Element {
+        self.fold_with(range.start, range.end, &mut vec![])
+    }
+    /// Answers `fold(l..r)` for every `(l, r)` in `ranges` in a single walk
+    /// of the tree: each node on the union of the queries' search paths is
+    /// visited once, and its cached fold goes to every query covering it.
+    pub fn prod_batch(&self, ranges: &[(usize, usize)]) -> Vec<T::Element> {
+        for &(l, r) in ranges {
+            assert!(l <= r && r <= self.len());
+        }
+        let mut out: Vec<_> = ranges.iter().map(|_| T::Element::zero()).collect();
+        let active: Vec<_> = (0..ranges.len())
+            .filter(|&q| ranges[q].0 < ranges[q].1)
+            .collect();
+        if let (Some(root), false) = (&self.root, active.is_empty()) {
+            Node::fold_batch(root, 0, ranges, &active, &mut out);
+        }
+        out
+    }
+    // Folds `[l, r)` from the cached folds of its covering subtrees, using
+    // `nodes` as scratch space.
+    fn fold_with<'a>(&'a self, l: usize, r: usize, nodes: &mut Vec<&'a Rc<Node<T>>>) -> T::Element {
+        assert!(l <= r && r <= self.len());
+        nodes.clear();
+        if let (Some(root), true) = (&self.root, l < r) {
+            Node::decompose(root, l, r, nodes);
+        }
+        nodes
+            .iter()
+            .fold(T::Element::zero(), |acc, node| acc + node.val().clone())
+    }
+    /// The fold of the whole sequence, read from the root in O(1), or
+    /// `zero()` when empty.
+    pub fn total(&self) -> T::Element {
+        self.root
+            .as_ref()
+            .map_or_else(T::Element::zero, |root| root.val().clone())
+    }
+    /// Returns the leftmost element of `[l, r)` with the least key, or `None`
+    /// if the range is empty.
+    pub fn min_range(&self, l: usize, r: usize) -> Option<&T::Element>
+    where
+        T::Element: Extrema,
+    {
+        self.extremum_range(l, r, false).map(|(_, leaf)| leaf.val())
+    }
+    /// Returns the leftmost element of `[l, r)` with the greatest key, or
+    /// `None` if the range is empty.
+    pub fn max_range(&self, l: usize, r: usize) -> Option<&T::Element>
+    where
+        T::Element: Extrema,
+    {
+        self.extremum_range(l, r, true).map(|(_, leaf)| leaf.val())
+    }
+    /// Returns the minimum, maximum, fold and length of the sequence, or
+    /// `None` if it is empty. The fold and length are read from the root and
+    /// the extrema found in O(log n).
+    pub fn stats(&self) -> Option<Stats<T::Element>>
+    where
+        T::Element: Extrema,
+    {
+        let root = self.root.as_ref()?;
+        Some(Stats {
+            min: self.min_range(0, root.len())?.clone(),
+            max: self.max_range(0, root.len())?.clone(),
+            sum: root.val().clone(),
+            count: root.len(),
+        })
+    }
+    /// Like `min_range`, but returns the index of the element.
+    pub fn argmin_range(&self, l: usize, r: usize) -> Option<usize>
+    where
+        T::Element: Extrema,
+    {
+        self.extremum_range(l, r, false).map(|(index, _)| index)
+    }
+    /// Like `max_range`, but returns the index of the element.
+    pub fn argmax_range(&self, l: usize, r: usize) -> Option<usize>
+    where
+        T::Element: Extrema,
+    {
+        self.extremum_range(l, r, true).map(|(index, _)| index)
+    }
+    fn extremum_range(&self, l: usize, r: usize, is_max: bool) -> Option<(usize, &Rc<Node<T>>)>
+    where
+        T::Element: Extrema,
+    {
+        assert!(l <= r && r <= self.len());
+        self.root
+            .as_ref()
+            .and_then(|root| Node::extremum_range(root, l, r, is_max))
+    }
+    /// Returns the largest `r` such that `pred(&self.fold(l..r))` holds.
+    ///
+    /// `pred` must be monotone (once false, false for every longer range) and
+    /// hold for `T::Element::zero()`.
+    pub fn max_right(&self, l: usize, pred: impl Fn(&T::Element) -> bool) -> usize {
+        let (_, right) = self.split(l);
+        l + right
+            .root
+            .as_ref()
+            .map_or(0, |root| root.max_right(T::Element::zero(), &pred).0)
+    }
+    /// Folds rightward from `l` until `pred` holds for the accumulated
+    /// product, returning the index of the element that made it hold along
+    /// with the product including that element. Returns `len()` and the fold
+    /// of `l..len()` if `pred` never holds.
+    ///
+    /// `pred` must be monotone (once true, true for every longer range) and
+    /// not hold for `T::Element::zero()`.
+    pub fn prod_until(&self, l: usize, pred: impl Fn(&T::Element) -> bool) -> (usize, T::Element) {
+        let (_, right) = self.split(l);
+        let Some(root) = right.root.as_ref() else {
+            return (l, T::Element::zero());
+        };
+        let (index, acc) = root.max_right(T::Element::zero(), &|x| !pred(x));
+        match right.get(index) {
+            Some(x) => (l + index, acc + x.clone()),
+            None => (self.len(), acc),
+        }
+    }
+    /// Picks an element with probability proportional to its weight in
+    /// O(log n), or returns `None` if the total weight is zero.
+    ///
+    /// `weight` must be additive over the monoid, so that the cached fold of
+    /// each subtree gives its total weight.
+    pub fn sample_weighted(
+        &self,
+        rng: &mut impl Rng,
+        weight: impl Fn(&T::Element) -> u64,
+    ) -> Option<&T::Element> {
+        let root = self.root.as_ref()?;
+        let total = weight(root.val());
+        if total == 0 {
+            return None;
+        }
+        let target = rng.gen_range(0, total);
+        let (index, _) = root.max_right(T::Element::zero(), &|x| weight(x) <= target);
+        self.get(index)
+    }
+    /// Returns whether `a[l..r] == b[l..r]`, skipping subtrees shared by both.
+    pub fn range_eq(a: &Self, b: &Self, l: usize, r: usize) -> bool
+    where
+        T::Element: PartialEq,
+    {
+        assert!(l <= r && r <= a.len() && r <= b.len());
+        Self::walks_eq(
+            InOrder::new(a.root.as_ref(), l),
+            InOrder::new(b.root.as_ref(), l),
+            r - l,
+        )
+    }
+    /// Returns whether `self[a] == self[b]`, which is `false` when the lengths
+    /// differ. Subtrees that both ranges reach through the same node are
+    /// skipped, so repeated blocks compare cheaply.
+    pub fn range_eq_self(&self, a: Range<usize>, b: Range<usize>) -> bool
+    where
+        T::Element: PartialEq,
+    {
+        assert!(a.start <= a.end && a.end <= self.len());
+        assert!(b.start <= b.end && b.end <= self.len());
+        a.len() == b.len()
+            && Self::walks_eq(
+                InOrder::new(self.root.as_ref(), a.start),
+                InOrder::new(self.root.as_ref(), b.start),
+                a.len(),
+            )
+    }
+    // Compares the next `remaining` elements of `a` and `b`.
+    fn walks_eq(mut a: InOrder<T>, mut b: InOrder<T>, mut remaining: usize) -> bool
+    where
+        T::Element: PartialEq,
+    {
+        while remaining > 0 {
+            let (x, y) = (a.peek().unwrap(), b.peek().unwrap());
+            if x.len() <= remaining && Rc::ptr_eq(x, y) || x.len() == 1 && y.len() == 1 {
+                if !Rc::ptr_eq(x, y) && x.val() != y.val() {
+                    return false;
+                }
+                remaining -= x.len();
+                a.pop();
+                b.pop();
+            } else if x.len() >= y.len() {
+                a.descend();
+            } else {
+                b.descend();
+            }
+        }
+        true
+    }
+    /// Returns the number of leading elements `a` and `b` have in common,
+    /// skipping subtrees shared by both.
+    pub fn common_prefix_len(a: &Self, b: &Self) -> usize
+    where
+        T::Element: PartialEq,
+    {
+        Self::common_len(
+            InOrder::new(a.root.as_ref(), 0),
+            InOrder::new(b.root.as_ref(), 0),
+        )
+    }
+    /// Whether the sequence begins with `prefix`.
+    pub fn starts_with(&self, prefix: &Self) -> bool
+    where
+        T::Element: PartialEq,
+    {
+        prefix.len() <= self.len() && Self::common_prefix_len(self, prefix) == prefix.len()
+    }
+    fn common_suffix_len(a: &Self, b: &Self) -> usize
+    where
+        T::Element: PartialEq,
+    {
+        Self::common_len(
+            InOrder::new_rev(a.root.as_ref(), a.len()),
+            InOrder::new_rev(b.root.as_ref(), b.len()),
+        )
+    }
+    fn common_len(mut a: InOrder<T>, mut b: InOrder<T>) -> usize
+    where
+        T::Element: PartialEq,
+    {
+        let mut len = 0;
+        while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+            if Rc::ptr_eq(x, y) || x.len() == 1 && y.len() == 1 {
+                if !Rc::ptr_eq(x, y) && x.val() != y.val() {
+                    break;
+                }
+                len += x.len();
+                a.pop();
+                b.pop();
+            } else if x.len() >= y.len() {
+                a.descend();
+            } else {
+                b.descend();
+            }
+        }
+        len
+    }
+    /// Returns edits that turn `old` into `new` when passed to `apply`. The
+    /// common prefix and suffix are skipped, jumping over shared subtrees,
+    /// and the rest is replaced position by position, so this is minimal for
+    /// a single changed region but not in general.
+    pub fn edit_script(old: &Self, new: &Self) -> Vec<Op<T::Element>>
+    where
+        T::Element: PartialEq,
+    {
+        let prefix = Self::common_prefix_len(old, new);
+        let suffix = Self::common_suffix_len(old, new).min(old.len().min(new.len()) - prefix);
+        let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+        let common = (old_end - prefix).min(new_end - prefix);
+        let mut script = vec![];
+        let pairs = old
+            .window_iter(prefix, prefix + common)
+            .zip(new.window_iter(prefix, prefix + common));
+        for (i, (x, y)) in (prefix..).zip(pairs) {
+            if x != y {
+                script.push(Op::Set(i, y.clone()));
+            }
+        }
+        let index = prefix + common;
+        script.extend((index..old_end).map(|_| Op::Erase(index)));
+        script.extend(
+            (index..)
+                .zip(new.window_iter(index, new_end))
+                .map(|(i, x)| Op::Insert(i, x.clone())),
+        );
+        script
+    }
+    /// Whether `other` is a rotation of `self`, found by matching `other`
+    /// against `self` repeated twice with Knuth-Morris-Pratt in O(n).
+    pub fn is_rotation_of(&self, other: &Self) -> bool
+    where
+        T::Element: PartialEq,
+    {
+        if self.len() != other.len() {
+            return false;
+        }
+        let pattern: Vec<_> = other.iter().collect();
+        let mut fail = vec![0; pattern.len() + 1];
+        for i in 1..pattern.len() {
+            let mut k = fail[i];
+            while k > 0 && pattern[i] != pattern[k] {
+                k = fail[k];
+            }
+            fail[i + 1] = if pattern[i] == pattern[k] { k + 1 } else { 0 };
+        }
+        let mut k = 0;
+        for x in self.iter().chain(self.iter()) {
+            if k == pattern.len() {
+                return true;
+            }
+            while k > 0 && pattern[k] != x {
+                k = fail[k];
+            }
+            if pattern[k] == x {
+                k += 1;
+            }
+        }
+        k == pattern.len()
+    }
+    /// Applies `f` to every element, mutating nodes that no other version
+    /// shares in place and copying the rest.
+    pub fn map_in_place(mut self, f: impl Fn(&mut T::Element)) -> Self {
+        if let Some(root) = self.root.as_mut() {
+            Node::map_in_place(root, &f);
+        }
+        self
+    }
+    /// Returns the element at `index`, or `None` if out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T::Element> {
+        self.root
+            .as_ref()
+            .filter(|root| index < root.len())
+            .map(|root| root.index(index))
+    }
+    /// Looks up several indices at once, with `None` for those out of bounds.
+    pub fn get_many<const N: usize>(&self, indices: [usize; N]) -> [Option<&T::Element>; N] {
+        indices.map(|index| self.get(index))
+    }
+    /// Returns the element at `index`, or `default` if out of bounds.
+    pub fn get_or<'a>(&'a self, index: usize, default: &'a T::Element) -> &'a T::Element {
+        self.get(index).unwrap_or(default)
+    }
+    /// Folds `f` over `[l, r)`, passing each element's index along with it.
+    pub fn range_scan<B>(
+        &self,
+        l: usize,
+        r: usize,
+        init: B,
+        mut f: impl FnMut(B, usize, &T::Element) -> B,
+    ) -> B {
+        assert!(l <= r && r <= self.len());
+        let mut acc = Some(init);
+        if let (Some(root), true) = (&self.root, l < r) {
+            root.visit_range(0, l, r, &mut |i, x| {
+                acc = Some(f(acc.take().unwrap(), i, x))
+            });
+        }
+        acc.unwrap()
+    }
+    /// Builds a balanced tree by splitting the known length in halves, with no
+    /// intermediate partial trees. Panics if the iterator yields fewer items
+    /// than it reports.
+    pub fn from_exact_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = T::Element>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        // Halving keeps every leaf at depth `height` or `height - 1`, so
+        // coloring the internal nodes at depth `height - 1` red equalizes the
+        // black heights.
+        fn build<T: LazyParam>(
+            n: usize,
+            depth: u32,
+            height: u32,
+            iter: &mut impl Iterator<Item = T::Element>,
+        ) -> Rc<Node<T>> {
+            if n == 1 {
+                let val = iter
+                    .next()
+                    .expect("iterator shorter than its reported length");
+                return Node::alloc(Leaf { val });
+            }
+            let left = build(n / 2, depth + 1, height, iter);
+            let right = build(n - n / 2, depth + 1, height, iter);
+            let color = if depth > 0 && depth + 1 == height {
+                Red
+            } else {
+                Black
+            };
+            Node::alloc(Node::new(color, left, right))
+        }
+        let mut iter = iter.into_iter();
+        match iter.len() {
+            0 => Self::new(),
+            n => {
+                let height = usize::BITS - (n - 1).leading_zeros();
+                Self::from_root(build(n, 0, height, &mut iter))
+            }
+        }
+    }
+    /// Splits into a gap buffer at `cursor`: the elements before it in order,
+    /// and those after it in reverse, so that both sides edit at their ends.
+    pub fn to_gap_buffer(&self, cursor: usize) -> (Vec<T::Element>, Vec<T::Element>) {
+        assert!(cursor <= self.len());
+        (
+            self.window_iter(0, cursor).cloned().collect(),
+            self.range_rev(cursor, self.len()).cloned().collect(),
+        )
+    }
+    /// The inverse of `to_gap_buffer`.
+    pub fn from_gap_buffer(left: Vec<T::Element>, right_rev: Vec<T::Element>) -> Self {
+        Self::merge_owned(
+            Self::from_exact_iter(left),
+            Self::from_exact_iter(right_rev.into_iter().rev()),
+        )
+    }
+    /// Builds a balanced tree from a non-decreasing sequence in O(n). The order
+    /// is only checked in debug builds.
+    pub fn from_sorted_iter<I: IntoIterator<Item = T::Element>>(iter: I) -> Self
+    where
+        T::Element: Ord,
+    {
+        let mut prev: Option<T::Element> = None;
+        iter.into_iter()
+            .inspect(|x| {
+                if cfg!(debug_assertions) {
+                    debug_assert!(prev.as_ref().is_none_or(|prev| prev <= x));
+                    prev = Some(x.clone());
+                }
+            })
+            .collect()
+    }
+    /// Returns the elements in non-decreasing order, keeping equal elements in
+    /// their original order. Sorts a copy and rebuilds, in O(n log n).
+    pub fn sort(&self) -> Self
+    where
+        T::Element: Ord,
+    {
+        let mut vec = self.to_vec();
+        vec.sort();
+        Self::from_sorted_iter(vec)
+    }
+    /// Applies `f` to every element in order, in O(n) total.
+    pub fn for_each(&self, f: impl FnMut(&T::Element)) {
+        self.iter().for_each(f)
+    }
+    /// Returns the O(log n) maximal subtrees that exactly cover `[l, r)`, in
+    /// order. Red subtrees get a blackened copy of their root; everything
+    /// below is shared.
+    pub fn decompose_range(&self, l: usize, r: usize) -> Vec<Self> {
+        assert!(l <= r && r <= self.len());
+        let mut nodes = vec![];
+        if let (Some(root), true) = (&self.root, l < r) {
+            Node::decompose(root, l, r, &mut nodes);
+        }
+        nodes
+            .into_iter()
+            .map(|node| Self::from_root(Node::to_black(node)))
+            .collect()
+    }
+    /// Whether `pred` holds for some element of `[l, r)`, stopping at the
+    /// first match.
+    pub fn any_in_range(&self, l: usize, r: usize, pred: impl Fn(&T::Element) -> bool) -> bool {
+        assert!(l <= r && r <= self.len());
+        match &self.root {
+            Some(root) if l < r => root.any_in_range(l, r, &pred),
+            _ => false,
+        }
+    }
+    /// Whether `pred` holds for every element of `[l, r)`, stopping at the
+    /// first counterexample.
+    pub fn all_in_range(&self, l: usize, r: usize, pred: impl Fn(&T::Element) -> bool) -> bool {
+        !self.any_in_range(l, r, |x| !pred(x))
+    }
+    /// Applies `f` to the elements of `[l, r)` in order, recursing only into
+    /// subtrees that overlap the range.
+    pub fn for_each_range(&self, l: usize, r: usize, mut f: impl FnMut(&T::Element)) {
+        assert!(l <= r && r <= self.len());
+        if let (Some(root), true) = (&self.root, l < r) {
+            root.visit_range(0, l, r, &mut |_, x| f(x));
+        }
+    }
+    /// Counts the elements of `[l, r)` less than `x` by scanning the range,
+    /// in O(r - l + log n).
+    pub fn count_less_in_range(&self, l: usize, r: usize, x: &T::Element) -> usize
+    where
+        T::Element: Ord,
+    {
+        let mut count = 0;
+        self.for_each_range(l, r, |y| count += (y < x) as usize);
+        count
+    }
+    pub fn to_vec(&self) -> Vec<T::Element> {
+        let mut vec = Vec::with_capacity(self.len());
+        self.for_each(|x| vec.push(x.clone()));
+        vec
+    }
+    /// Like `to_vec`, but moves values out of the leaves that no other version
+    /// shares, cloning only the rest. Also returns how many values moved.
+    pub fn into_vec_reusing(self) -> (Vec<T::Element>, usize) {
+        fn drain<T: LazyParam>(node: Rc<Node<T>>, vec: &mut Vec<T::Element>, moved: &mut usize) {
+            match Rc::try_unwrap(node) {
+                Ok(Leaf { val }) => {
+                    vec.push(val);
+                    *moved += 1;
+                }
+                Ok(Tree { left, right, .. }) => {
+                    drain(left, vec, moved);
+                    drain(right, vec, moved);
+                }
+                Err(node) => vec.extend(InOrder::new(Some(&node), 0).cloned()),
+            }
+        }
+        let mut vec = Vec::with_capacity(self.len());
+        let mut moved = 0;
+        if let Some(root) = self.root {
+            drain(root, &mut vec, &mut moved);
+        }
+        (vec, moved)
+    }
+    /// Builds the sorted sequence of the keys of `map` in O(n), for rank and
+    /// select queries on a persistent copy.
+    pub fn from_sorted_map_keys<V>(map: &BTreeMap<T::Element, V>) -> Self
+    where
+        T::Element: Ord,
+    {
+        Self::from_exact_iter(map.keys().cloned())
+    }
+    /// Rebuilds the sequence into a tree of minimal height, `ceil(log2(len()))`.
+    pub fn rebalance(&self) -> Self {
+        Self::from_exact_iter(self.to_vec())
+    }
+    /// Rebuilds the sequence in the shape `collect` produces, which depends
+    /// only on the length, so equal sequences canonicalize to equal shapes.
+    pub fn canonicalize(&self) -> Self {
+        self.iter().cloned().collect()
+    }
+    /// Returns `[start, end)` of the maximal run around `index` in which
+    /// every adjacent pair satisfies `same`, in O(log n + end - start).
+    pub fn block_bounds(
+        &self,
+        index: usize,
+        same: impl Fn(&T::Element, &T::Element) -> bool,
+    ) -> (usize, usize) {
+        assert!(index < self.len());
+        // The number of pairs satisfying `same` before the first that fails,
+        // with each pair passed in sequence order.
+        let run = |mut iter: InOrder<T>| {
+            let rev = iter.rev;
+            let mut prev = iter.next().unwrap();
+            iter.take_while(|x| {
+                let prev = mem::replace(&mut prev, x);
+                if rev {
+                    same(x, prev)
+                } else {
+                    same(prev, x)
+                }
+            })
+            .count()
+        };
+        let after = run(InOrder::new(self.root.as_ref(), index));
+        let before = run(InOrder::new_rev(self.root.as_ref(), index + 1));
+        (index - before, index + 1 + after)
+    }
+    /// Iterates over `[l, r)` backwards, from `r - 1` down to `l`, in
+    /// O(log n + r - l) without building any subtree.
+    pub fn range_rev(&self, l: usize, r: usize) -> impl Iterator<Item = &T::Element> + '_ {
+        assert!(l <= r && r <= self.len());
+        InOrder::new_rev(self.root.as_ref(), r).take(r - l)
+    }
+    /// Iterates over `[l, r)` with an explicit stack, in O(log n + r - l)
+    /// without building any subtree.
+    pub fn window_iter(&self, l: usize, r: usize) -> impl Iterator<Item = &T::Element> + '_ {
+        assert!(l <= r && r <= self.len());
+        InOrder::new(self.root.as_ref(), l).take(r - l)
+    }
+    /// Yields clones of the elements in order, walking the tree once.
+    pub fn into_cloned_iter(&self) -> impl Iterator<Item = T::Element> + '_ {
+        self.iter().cloned()
+    }
+    /// Panics with the first differing index, both values and both lengths
+    /// unless the sequence equals `v`.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn assert_eq_vec(&self, v: &[T::Element])
+    where
+        T::Element: PartialEq + fmt::Debug,
+    {
+        let mut cursor = InOrder::new(self.root.as_ref(), 0);
+        let mut expected = v.iter();
+        for i in 0.. {
+            match (cursor.next(), expected.next()) {
+                (None, None) => return,
+                (x, y) if x == y => {}
+                (x, y) => panic!(
+                    "sequences differ at index {}: tree has {:?}, slice has {:?} (lengths {} and {})",
+                    i,
+                    x,
+                    y,
+                    self.len(),
+                    v.len()
+                ),
+            }
+        }
+    }
+    /// Returns a value whose `Debug` output shows the node structure, with
+    /// colors and ranks, instead of just the elements.
+    pub fn debug_structure(&self) -> impl fmt::Debug + '_
+    where
+        T::Element: fmt::Debug,
+    {
+        self.root.as_deref().map(Structure)
+    }
+    pub fn rev(&self) -> Rev<Iter<'_, T>> {
+        self.iter().rev()
+    }
+    /// Iterates in index order. Like `for_each` and `to_vec`, the order
+    /// depends only on the sequence, never on the shape of the tree.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            front: InOrder::new(self.root.as_ref(), 0),
+            back: InOrder::new_rev(self.root.as_ref(), self.len()),
+            len: self.len(),
+        }
+    }
+}
+impl<T: LazyParam> Default for PersistentLazyRBTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// Formats as the element sequence, like a `Vec`. See `debug_structure` for
+/// the node layout.
+impl<T: LazyParam> fmt::Debug for PersistentLazyRBTree<T>
+where
+    T::Element: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+// Formats a subtree as nested nodes named by color, with leaves as their
+// values.
+struct Structure<'a, T: LazyParam>(&'a Node<T>);
+impl<'a, T: LazyParam> fmt::Debug for Structure<'a, T>
+where
+    T::Element: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Leaf { val } => val.fmt(f),
+            Tree {
+                color,
+                rank,
+                left,
+                right,
+                ..
+            } => f
+                .debug_struct(match color {
+                    Red => "Red",
+                    Black => "Black",
+                })
+                .field("rank", rank)
+                .field("left", &Structure(left))
+                .field("right", &Structure(right))
+                .finish(),
+        }
+    }
+}
+impl<T: LazyParam> Clone for PersistentLazyRBTree<T> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+        }
+    }
+}
+impl<T: LazyParam> Index<usize> for PersistentLazyRBTree<T> {
+    type Output = T::Element;
+    fn index(&self, index: usize) -> &Self::Output {
+        self.get(index).unwrap_or_else(|| {
+            panic!(
+                "{}",
+                SeqError::OutOfBounds {
+                    index,
+                    len: self.len(),
+                }
+            )
+        })
+    }
+}
+// Walks from both ends at once; `len` counts the elements neither end has
+// yielded yet, which keeps the two walks from crossing.
+pub struct Iter<'a, T: LazyParam> {
+    front: InOrder<'a, T>,
+    back: InOrder<'a, T>,
+    len: usize,
+}
+impl<'a, T: LazyParam> Iterator for Iter<'a, T> {
+    type Item = &'a T::Element;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.len > 0 {
+            self.len -= 1;
+            self.front.next()
+        } else {
+            None
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+impl<'a, T: LazyParam> ExactSizeIterator for Iter<'a, T> {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+impl<'a, T: LazyParam> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.len > 0 {
+            self.len -= 1;
+            self.back.next()
+        } else {
+            None
+        }
+    }
+}
+impl<'a, T: LazyParam> FusedIterator for Iter<'a, T> {}
+impl<T: LazyParam> PartialEq for PersistentLazyRBTree<T>
+where
+    T::Element: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && Self::range_eq(self, other, 0, self.len())
+    }
+}
+impl<T: LazyParam> Eq for PersistentLazyRBTree<T> where T::Element: Eq {}
+/// Hashes the length and then each element in order, so the result depends
+/// only on the element sequence and not on the shape of the tree.
+impl<T: LazyParam> Hash for PersistentLazyRBTree<T>
+where
+    T::Element: Hash,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        self.for_each(|x| x.hash(state));
+    }
+}
+impl<T: LazyParam, const N: usize> From<[T::Element; N]> for PersistentLazyRBTree<T> {
+    fn from(items: [T::Element; N]) -> Self {
+        Self::from_exact_iter(items)
+    }
+}
+impl<'a, T: LazyParam> IntoIterator for &'a PersistentLazyRBTree<T> {
+    type Item = &'a T::Element;
+    type IntoIter = Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+impl<T: LazyParam> FromIterator<T::Element> for PersistentLazyRBTree<T> {
     fn from_iter<I: IntoIterator<Item = T::Element>>(iter: I) -> Self {
         let mut res: Vec<Self> = vec![];
         for item in iter {
@@ -319,15 +2103,168 @@ impl<T: LazyParam> FromIterator<T::Element> for PersistentLazyRBTree<T> {
             let left = res.pop().unwrap();
             res.push(Self::merge(&left, &right));
         }
-        res.remove(0)
+        res.pop().unwrap_or_default()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{LazyParam, Magma, Monoid, PersistentLazyRBTree, Semigroup};
+    use crate::Color::{Black, Red};
+    use crate::Node::{Leaf, Tree};
+    use crate::{
+        take_alloc_count, take_op_stats, Extrema, InOrder, LazyParam, Magma, Monoid, Node, Op,
+        OpStats, Pair, PersistentLazyRBTree, Semigroup, SeqError, Snapshot, Stats, NODE_VISITS,
+    };
     use rand::Rng;
-    use std::ops::Add;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::ops::{Add, Bound};
+    use std::rc::Rc;
+
+    struct CountingAllocator;
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    pub(crate) fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let res = f();
+        (res, ALLOCATIONS.with(Cell::get) - before)
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub(crate) struct Sum(pub(crate) i64);
+    impl Add for Sum {
+        type Output = Self;
+        fn add(self, other: Self) -> Self {
+            Self(self.0 + other.0)
+        }
+    }
+    impl Magma for Sum {}
+    impl Semigroup for Sum {}
+    impl Monoid for Sum {
+        fn zero() -> Self {
+            Self(0)
+        }
+    }
+    impl LazyParam for Sum {
+        type Element = Sum;
+        type Mapping = Sum;
+        fn apply(_mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+            element
+        }
+    }
+
+    // A key with a tag riding along, compared by key alone, so that tests can
+    // tell apart elements that compare equal. Both parts add up.
+    #[derive(Clone, Copy, Debug)]
+    pub(crate) struct Keyed(pub(crate) i64, pub(crate) usize);
+    impl PartialEq for Keyed {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+    impl Eq for Keyed {}
+    impl PartialOrd for Keyed {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Keyed {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+    impl Add for Keyed {
+        type Output = Self;
+        fn add(self, other: Self) -> Self {
+            Self(self.0 + other.0, self.1 + other.1)
+        }
+    }
+    impl Magma for Keyed {}
+    impl Semigroup for Keyed {}
+    impl Monoid for Keyed {
+        fn zero() -> Self {
+            Self(0, 0)
+        }
+    }
+    impl LazyParam for Keyed {
+        type Element = Keyed;
+        type Mapping = Sum;
+        fn apply(_mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+            element
+        }
+    }
+
+    // Folds to the least and greatest values, for range extremum queries.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub(crate) struct MinMax(pub(crate) Option<(i64, i64)>);
+    impl MinMax {
+        pub(crate) fn new(x: i64) -> Self {
+            Self(Some((x, x)))
+        }
+    }
+    impl Add for MinMax {
+        type Output = Self;
+        fn add(self, other: Self) -> Self {
+            match (self.0, other.0) {
+                (Some((a, b)), Some((c, d))) => Self(Some((a.min(c), b.max(d)))),
+                (x, y) => Self(x.or(y)),
+            }
+        }
+    }
+    impl Magma for MinMax {}
+    impl Semigroup for MinMax {}
+    impl Monoid for MinMax {
+        fn zero() -> Self {
+            Self(None)
+        }
+    }
+    impl Extrema for MinMax {
+        type Key = i64;
+        fn min_key(&self) -> Option<&i64> {
+            self.0.as_ref().map(|(min, _)| min)
+        }
+        fn max_key(&self) -> Option<&i64> {
+            self.0.as_ref().map(|(_, max)| max)
+        }
+    }
+    impl LazyParam for MinMax {
+        type Element = MinMax;
+        type Mapping = Sum;
+        fn apply(_mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+            element
+        }
+    }
+
+    pub(crate) fn random_tree(n: usize, m: i64) -> (Vec<i64>, PersistentLazyRBTree<Sum>) {
+        let mut rng = rand::thread_rng();
+        let mut vec = Vec::new();
+        let mut rbtree = PersistentLazyRBTree::new();
+        for _ in 0..n {
+            let x = rng.gen_range(0, m);
+            let i = rng.gen_range(0, vec.len() + 1);
+            vec.insert(i, x);
+            rbtree = rbtree.insert(i, Sum(x));
+        }
+        (vec, rbtree)
+    }
+
+    pub(crate) fn values(rbtree: &PersistentLazyRBTree<Sum>) -> Vec<i64> {
+        rbtree.iter().map(|x| x.0).collect()
+    }
+
     #[test]
     fn it_works() {
         #[derive(Clone)]
@@ -349,7 +2286,7 @@ mod tests {
         impl LazyParam for LazySum {
             type Mapping = SumMonoid;
             type Element = SumMonoid;
-            fn apply(mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+            fn apply(_mapping: Self::Mapping, element: Self::Element) -> Self::Element {
                 element
             }
         }
@@ -380,9 +2317,1800 @@ mod tests {
             let l = rng.gen_range(0, vec.len());
             let r = rng.gen_range(l, vec.len() + 1);
             assert_eq!(
-                vec.get(l..r).unwrap().iter().fold(0, |sum, i| sum + i),
+                vec.get(l..r).unwrap().iter().sum::<i64>(),
                 rbtree.fold(l..r).0
             );
         }
     }
+
+    #[test]
+    fn min_max_range() {
+        let mut rng = rand::thread_rng();
+        let (mut vec, _) = random_tree(1000, 50);
+        let mut rbtree: PersistentLazyRBTree<MinMax> =
+            vec.iter().map(|&x| MinMax::new(x)).collect();
+        for _ in 0..1000 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            assert_eq!(
+                vec[l..r].iter().min(),
+                rbtree.min_range(l, r).and_then(MinMax::min_key)
+            );
+            assert_eq!(
+                vec[l..r].iter().max(),
+                rbtree.max_range(l, r).and_then(MinMax::max_key)
+            );
+
+            let x = rng.gen_range(0, 50);
+            let i = rng.gen_range(0, vec.len() + 1);
+            vec.insert(i, x);
+            rbtree = rbtree.insert(i, MinMax::new(x));
+            let i = rng.gen_range(0, vec.len());
+            vec.remove(i);
+            rbtree = rbtree.erase(i);
+        }
+        assert_eq!(None, rbtree.min_range(3, 3));
+        assert_eq!(None, PersistentLazyRBTree::<MinMax>::new().max_range(0, 0));
+    }
+
+    #[test]
+    fn argmin_argmax_range() {
+        let mut rng = rand::thread_rng();
+        let (vec, _) = random_tree(1000, 5);
+        let rbtree: PersistentLazyRBTree<MinMax> = vec.iter().map(|&x| MinMax::new(x)).collect();
+        for _ in 0..1000 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let argmin = (l..r).fold(None, |best: Option<usize>, i| match best {
+                Some(j) if vec[j] <= vec[i] => Some(j),
+                _ => Some(i),
+            });
+            let argmax = (l..r).fold(None, |best: Option<usize>, i| match best {
+                Some(j) if vec[j] >= vec[i] => Some(j),
+                _ => Some(i),
+            });
+            assert_eq!(argmin, rbtree.argmin_range(l, r));
+            assert_eq!(argmax, rbtree.argmax_range(l, r));
+        }
+    }
+
+    #[test]
+    fn merge_allocations() {
+        let left: PersistentLazyRBTree<Sum> = (0..8).map(Sum).collect();
+        let right: PersistentLazyRBTree<Sum> = (8..16).map(Sum).collect();
+        let (merged, allocations) =
+            count_allocations(|| PersistentLazyRBTree::merge(&left, &right));
+        assert_eq!(1, allocations);
+        assert!(merged.iter().map(|x| x.0).eq(0..16));
+
+        let (vec, rbtree) = random_tree(1000, 100);
+        for i in 0..=vec.len() {
+            let (left, right) = rbtree.split(i);
+            assert!(left.iter().map(|x| x.0).eq(vec[..i].iter().copied()));
+            assert!(right.iter().map(|x| x.0).eq(vec[i..].iter().copied()));
+        }
+    }
+
+    #[test]
+    fn split_next_to_red_sibling() {
+        let leaf = || Node::alloc(Leaf { val: Sum(0) });
+        let node = |color, left, right| Node::alloc(Node::new(color, left, right));
+        // B(R(B(L, L), B(L, L)), R(B(L, L), B(L, R(L, L)))), where splitting
+        // inside the right child's right child merges into its red sibling.
+        let pair = |color| node(color, leaf(), leaf());
+        let rbtree = PersistentLazyRBTree::<Sum>::from_root(node(
+            Black,
+            node(Red, pair(Black), pair(Black)),
+            node(Red, pair(Black), node(Black, leaf(), pair(Red))),
+        ));
+        assert!(rbtree.check_invariants());
+        for index in 0..=rbtree.len() {
+            let (left, right) = rbtree.split(index);
+            assert!(left.check_invariants() && right.check_invariants());
+        }
+    }
+
+    #[test]
+    fn split_at_many() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(200, 100);
+        for _ in 0..100 {
+            let mut indices: Vec<usize> = (0..5).map(|_| rng.gen_range(0, vec.len() + 1)).collect();
+            indices.sort();
+            indices.dedup();
+            let segments = rbtree.split_at_many(&indices);
+            assert_eq!(indices.len() + 1, segments.len());
+            let mut offset = 0;
+            for (segment, &index) in segments.iter().zip(&indices) {
+                offset += segment.len();
+                assert_eq!(index, offset);
+            }
+            let joined = segments
+                .iter()
+                .fold(PersistentLazyRBTree::new(), |acc, segment| {
+                    PersistentLazyRBTree::merge(&acc, segment)
+                });
+            assert_eq!(vec, values(&joined));
+        }
+        assert_eq!(1, rbtree.split_at_many(&[]).len());
+        assert_eq!(vec, values(&rbtree.split_at_many(&[0, vec.len()])[1]));
+    }
+
+    #[test]
+    fn take_skip() {
+        let (vec, rbtree) = random_tree(100, 100);
+        for n in 0..=vec.len() + 2 {
+            let m = n.min(vec.len());
+            assert_eq!(vec[..m], values(&rbtree.take(n)));
+            assert_eq!(vec[m..], values(&rbtree.skip(n)));
+        }
+        assert!(PersistentLazyRBTree::<Sum>::new().take(1).is_empty());
+        assert!(PersistentLazyRBTree::<Sum>::new().skip(1).is_empty());
+    }
+
+    #[test]
+    fn zip() {
+        let (a, rbtree_a) = random_tree(100, 100);
+        let (b, rbtree_b) = random_tree(70, 100);
+        let zipped = rbtree_a.zip(&rbtree_b);
+        assert_eq!(70, zipped.len());
+        assert!(zipped
+            .iter()
+            .map(|Pair(x, y)| (x.0, y.0))
+            .eq(a.iter().copied().zip(b.iter().copied())));
+        assert_eq!(
+            Pair(Sum(a[..70].iter().sum()), Sum(b.iter().sum())),
+            zipped.fold(0..70)
+        );
+        assert_eq!(70, rbtree_b.zip(&rbtree_a).len());
+    }
+
+    #[test]
+    fn interleave() {
+        for (n, m) in [(0, 0), (0, 5), (5, 0), (10, 10), (10, 3), (3, 10)] {
+            let (a, rbtree_a) = random_tree(n, 100);
+            let (b, rbtree_b) = random_tree(m, 100);
+            let mut expected: Vec<i64> = vec![];
+            for i in 0..n.max(m) {
+                expected.extend(a.get(i));
+                expected.extend(b.get(i));
+            }
+            assert_eq!(
+                expected,
+                values(&PersistentLazyRBTree::interleave(&rbtree_a, &rbtree_b))
+            );
+        }
+    }
+
+    #[test]
+    fn insert_by() {
+        let mut rng = rand::thread_rng();
+        let comparisons = Cell::new(0);
+        let cmp = |a: &Sum, b: &Sum| {
+            comparisons.set(comparisons.get() + 1);
+            (a.0 / 10).cmp(&(b.0 / 10))
+        };
+        let mut vec: Vec<i64> = vec![];
+        let mut rbtree = PersistentLazyRBTree::new();
+        for _ in 0..500 {
+            let x = rng.gen_range(0, 100);
+            vec.insert(vec.partition_point(|y| y / 10 <= x / 10), x);
+            comparisons.set(0);
+            let height = rbtree.height();
+            rbtree = rbtree.insert_by(Sum(x), cmp);
+            // One comparison per level on the way down, and one at the leaf.
+            assert!(comparisons.get() <= height + 1);
+        }
+        assert_eq!(vec, values(&rbtree));
+    }
+
+    #[test]
+    fn range_eq() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(300, 3);
+        for _ in 0..300 {
+            let (mut other_vec, mut other) = (vec.clone(), rbtree.clone());
+            for _ in 0..rng.gen_range(0, 3) {
+                let i = rng.gen_range(0, other_vec.len());
+                let x = rng.gen_range(0, 3);
+                other_vec[i] = x;
+                other = other.erase(i).insert(i, Sum(x));
+            }
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            assert_eq!(
+                vec[l..r] == other_vec[l..r],
+                PersistentLazyRBTree::range_eq(&rbtree, &other, l, r)
+            );
+        }
+        let (other_vec, other) = random_tree(200, 3);
+        for l in 0..=200 {
+            assert_eq!(
+                vec[l..200] == other_vec[l..200],
+                PersistentLazyRBTree::range_eq(&rbtree, &other, l, 200)
+            );
+        }
+    }
+
+    #[test]
+    fn try_insert_erase() {
+        let (vec, rbtree) = random_tree(10, 100);
+        assert_eq!(
+            Err(SeqError::OutOfBounds { index: 11, len: 10 }),
+            rbtree.try_insert(11, Sum(0)).map(|_| ())
+        );
+        assert_eq!(
+            Err(SeqError::OutOfBounds { index: 10, len: 10 }),
+            rbtree.try_erase(10).map(|_| ())
+        );
+        assert_eq!(
+            "index 10 out of bounds for sequence of length 10",
+            rbtree.try_erase(10).err().unwrap().to_string()
+        );
+        let mut expected = vec.clone();
+        expected.insert(10, 7);
+        assert_eq!(expected, values(&rbtree.try_insert(10, Sum(7)).unwrap()));
+        assert_eq!(vec[1..], values(&rbtree.try_erase(0).unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "index 3 out of bounds for sequence of length 2")]
+    fn insert_out_of_bounds() {
+        PersistentLazyRBTree::<Sum>::from_iter([Sum(1), Sum(2)]).insert(3, Sum(0));
+    }
+
+    #[test]
+    fn stats() {
+        assert_eq!(None, PersistentLazyRBTree::<MinMax>::new().stats());
+        let (vec, _) = random_tree(500, 1000);
+        let rbtree: PersistentLazyRBTree<MinMax> = vec.iter().map(|&x| MinMax::new(x)).collect();
+        let (min, max) = (*vec.iter().min().unwrap(), *vec.iter().max().unwrap());
+        assert_eq!(
+            Some(Stats {
+                min: MinMax::new(min),
+                max: MinMax::new(max),
+                sum: MinMax(Some((min, max))),
+                count: vec.len(),
+            }),
+            rbtree.stats()
+        );
+    }
+
+    #[test]
+    fn rev() {
+        let (vec, rbtree) = random_tree(100, 100);
+        assert!(rbtree.rev().map(|x| x.0).eq(vec.iter().rev().copied()));
+
+        let mut iter = rbtree.iter();
+        let mut front = vec![];
+        let mut back = vec![];
+        for i in 0.. {
+            assert_eq!(vec.len() - front.len() - back.len(), iter.len());
+            match if i % 3 == 0 {
+                iter.next_back()
+            } else {
+                iter.next()
+            } {
+                Some(x) if i % 3 == 0 => back.push(x.0),
+                Some(x) => front.push(x.0),
+                None => break,
+            }
+        }
+        assert_eq!(None, iter.next());
+        front.extend(back.iter().rev());
+        assert_eq!(vec, front);
+    }
+
+    #[test]
+    fn map_in_place() {
+        let (vec, rbtree) = random_tree(500, 100);
+        let doubled: Vec<i64> = vec.iter().map(|x| x * 2).collect();
+
+        let shared = rbtree.clone();
+        let mapped = rbtree.map_in_place(|x| x.0 *= 2);
+        assert_eq!(vec, values(&shared));
+        assert_eq!(doubled, values(&mapped));
+        drop(shared);
+
+        let (mapped, allocations) = count_allocations(|| mapped.map_in_place(|x| x.0 += 1));
+        assert_eq!(0, allocations);
+        assert!(mapped.iter().map(|x| x.0).eq(doubled.iter().map(|x| x + 1)));
+        assert_eq!(Sum(doubled.iter().sum::<i64>() + 500), mapped.fold(0..500));
+
+        let (partly_shared, rbtree) = (mapped.erase(0), mapped);
+        let mapped = rbtree.map_in_place(|x| x.0 = 0);
+        assert!(mapped.iter().all(|x| x.0 == 0));
+        assert!(partly_shared
+            .iter()
+            .map(|x| x.0)
+            .eq(doubled[1..].iter().map(|x| x + 1)));
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn try_fold() {
+        let (vec, rbtree) = random_tree(50, 100);
+        assert_eq!(Ok(Sum(vec[3..20].iter().sum())), rbtree.try_fold(3..20));
+        assert_eq!(Ok(Sum(0)), rbtree.try_fold(7..7));
+        assert_eq!(Ok(Sum(0)), rbtree.try_fold(50..50));
+        assert_eq!(
+            Err(SeqError::InvalidRange {
+                l: 0,
+                r: 51,
+                len: 50
+            }),
+            rbtree.try_fold(0..51)
+        );
+        assert_eq!(
+            Err(SeqError::InvalidRange {
+                l: 20,
+                r: 3,
+                len: 50
+            }),
+            rbtree.try_fold(20..3)
+        );
+        assert_eq!(
+            Ok(Sum(0)),
+            PersistentLazyRBTree::<Sum>::new().try_fold(0..0)
+        );
+    }
+
+    #[test]
+    fn root_rank_and_color() {
+        let empty = PersistentLazyRBTree::<Sum>::new();
+        assert_eq!((0, true), (empty.root_rank(), empty.root_is_black()));
+        let leaf = empty.insert(0, Sum(1));
+        assert_eq!((0, true), (leaf.root_rank(), leaf.root_is_black()));
+        for n in [2, 4, 8, 1024] {
+            let rbtree: PersistentLazyRBTree<Sum> = (0..n).map(Sum).collect();
+            assert_eq!(n.trailing_zeros() as usize, rbtree.root_rank());
+            assert!(rbtree.root_is_black());
+        }
+        let (_, rbtree) = random_tree(1000, 100);
+        assert!(rbtree.root_is_black());
+        assert!((5..=20).contains(&rbtree.root_rank()));
+    }
+
+    #[test]
+    fn canonicalize() {
+        let (vec, rbtree) = random_tree(200, 100);
+        let other = vec
+            .iter()
+            .rev()
+            .fold(PersistentLazyRBTree::<Sum>::new(), |acc, &x| {
+                acc.insert(0, Sum(x))
+            });
+        assert_ne!(rbtree.to_dot(), other.to_dot());
+        assert_eq!(
+            rbtree.canonicalize().to_dot(),
+            other.canonicalize().to_dot()
+        );
+        assert_eq!(vec, values(&rbtree.canonicalize()));
+    }
+
+    #[test]
+    fn splice_iter() {
+        let mut rng = rand::thread_rng();
+        let (mut vec, mut rbtree) = random_tree(100, 100);
+        for _ in 0..100 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let replacement: Vec<i64> = (0..rng.gen_range(0, 10)).collect();
+            vec.splice(l..r, replacement.iter().copied());
+            rbtree = rbtree.splice_iter(l, r, replacement.into_iter().map(Sum));
+            assert_eq!(vec, values(&rbtree));
+        }
+    }
+
+    #[test]
+    fn trim() {
+        for _ in 0..100 {
+            let (vec, rbtree) = random_tree(30, 3);
+            let pred = |x: &Sum| x.0 != 0;
+            let start = vec.iter().position(|&x| x == 0).unwrap_or(vec.len());
+            let end = vec.iter().rposition(|&x| x == 0).map_or(0, |i| i + 1);
+            assert_eq!(vec[start..], values(&rbtree.trim_start(pred)));
+            assert_eq!(vec[..end], values(&rbtree.trim_end(pred)));
+        }
+        let rbtree: PersistentLazyRBTree<Sum> = (1..10).map(Sum).collect();
+        assert!(rbtree.trim_start(|x| x.0 > 0).is_empty());
+        assert!(rbtree.trim_end(|x| x.0 > 0).is_empty());
+        assert_eq!(9, rbtree.trim_end(|x| x.0 > 9).len());
+    }
+
+    #[test]
+    fn join() {
+        let (sep_vec, sep) = random_tree(3, 100);
+        assert!(PersistentLazyRBTree::join(&[], &sep).is_empty());
+        for k in 1..10 {
+            let (vecs, parts): (Vec<_>, Vec<_>) =
+                (0..k).map(|i| random_tree(i * 7 % 5, 100)).unzip();
+            let joined = PersistentLazyRBTree::join(&parts, &sep);
+            assert_eq!(vecs.join(&sep_vec[..]), values(&joined));
+        }
+    }
+
+    #[test]
+    fn node_allocations() {
+        let rbtree: PersistentLazyRBTree<Sum> = (0..1024).map(Sum).collect();
+        let height = 2 * rbtree.root_rank() + 1;
+        take_alloc_count();
+        for i in (0..=1024).step_by(97) {
+            let inserted = rbtree.insert(i, Sum(-1));
+            assert!(take_alloc_count() <= 4 * height);
+            assert_eq!(Some(&Sum(-1)), inserted.iter().nth(i));
+        }
+        for i in (0..1024).step_by(97) {
+            let set = rbtree.set(i, Sum(-1));
+            assert_eq!(11, take_alloc_count());
+            assert_eq!(Some(&Sum(-1)), set.iter().nth(i));
+            assert_eq!(Sum(1023 * 512 - 1 - i as i64), set.fold(0..1024));
+        }
+        assert!(rbtree.iter().map(|x| x.0).eq(0..1024));
+    }
+
+    #[test]
+    fn get_or() {
+        let (vec, rbtree) = random_tree(10, 100);
+        let default = Sum(-1);
+        for i in 0..12 {
+            assert_eq!(vec.get(i).copied(), rbtree.get(i).map(|x| x.0));
+            assert_eq!(*vec.get(i).unwrap_or(&-1), rbtree.get_or(i, &default).0);
+        }
+        assert_eq!(None, PersistentLazyRBTree::<Sum>::new().get(0));
+    }
+
+    #[test]
+    fn range_scan() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(300, 100);
+        for _ in 0..100 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let mut expected = vec![];
+            for i in l..r {
+                expected.push((i, rbtree.get(i).unwrap().0));
+            }
+            let scanned = rbtree.range_scan(l, r, vec![], |mut acc, i, x| {
+                acc.push((i, x.0));
+                acc
+            });
+            assert_eq!(expected, scanned);
+            let weighted: i64 = (l..r).map(|i| i as i64 * vec[i]).sum();
+            assert_eq!(
+                weighted,
+                rbtree.range_scan(l, r, 0, |acc, i, x| acc + i as i64 * x.0)
+            );
+        }
+    }
+
+    #[test]
+    fn remove_first() {
+        let (vec, rbtree) = random_tree(50, 10);
+        for x in 0..10 {
+            match vec.iter().position(|&y| y == x) {
+                Some(i) => {
+                    let mut expected = vec.clone();
+                    expected.remove(i);
+                    assert_eq!(
+                        Some(expected),
+                        rbtree.remove_first(&Sum(x)).map(|t| values(&t))
+                    );
+                }
+                None => assert!(rbtree.remove_first(&Sum(x)).is_none()),
+            }
+        }
+        assert!(rbtree.remove_first(&Sum(10)).is_none());
+        assert!(PersistentLazyRBTree::<Sum>::new()
+            .remove_first(&Sum(0))
+            .is_none());
+    }
+
+    #[test]
+    fn group_by() {
+        let (vec, rbtree) = random_tree(100, 4);
+        let mut expected: Vec<(bool, Vec<i64>)> = vec![];
+        for &x in &vec {
+            match expected.last_mut() {
+                Some((k, run)) if *k == (x % 2 == 0) => run.push(x),
+                _ => expected.push((x % 2 == 0, vec![x])),
+            }
+        }
+        let groups: Vec<(bool, Vec<i64>)> = rbtree
+            .group_by(|x| x.0 % 2 == 0)
+            .iter()
+            .map(|(k, run)| (*k, values(run)))
+            .collect();
+        assert_eq!(expected, groups);
+        assert!(PersistentLazyRBTree::<Sum>::new()
+            .group_by(|x| x.0)
+            .is_empty());
+    }
+
+    #[test]
+    fn overwrite_range() {
+        let mut rng = rand::thread_rng();
+        let (mut vec, mut rbtree) = random_tree(100, 100);
+        for _ in 0..100 {
+            let (src_vec, src) = random_tree(rng.gen_range(0, 20), 100);
+            let start = rng.gen_range(0, vec.len() - src_vec.len() + 1);
+            vec[start..start + src_vec.len()].copy_from_slice(&src_vec);
+            rbtree = rbtree.overwrite_range(start, &src);
+            assert_eq!(vec, values(&rbtree));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "assertion failed")]
+    fn overwrite_range_overflow() {
+        let rbtree: PersistentLazyRBTree<Sum> = (0..10).map(Sum).collect();
+        rbtree.overwrite_range(usize::MAX, &rbtree.take(1));
+    }
+
+    #[test]
+    fn into_cloned_iter() {
+        thread_local! {
+            static CLONES: Cell<usize> = const { Cell::new(0) };
+        }
+        #[derive(Debug, PartialEq)]
+        struct Counted(i64);
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                CLONES.with(|clones| clones.set(clones.get() + 1));
+                Self(self.0)
+            }
+        }
+        impl Add for Counted {
+            type Output = Self;
+            fn add(self, other: Self) -> Self {
+                Self(self.0 + other.0)
+            }
+        }
+        impl Magma for Counted {}
+        impl Semigroup for Counted {}
+        impl Monoid for Counted {
+            fn zero() -> Self {
+                Self(0)
+            }
+        }
+        impl LazyParam for Counted {
+            type Element = Counted;
+            type Mapping = Sum;
+            fn apply(_mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+                element
+            }
+        }
+
+        let (vec, rbtree) = random_tree(1000, 100);
+        assert!(rbtree
+            .into_cloned_iter()
+            .map(|x| x.0)
+            .eq(vec.iter().copied()));
+        let rbtree: PersistentLazyRBTree<Counted> = vec.iter().map(|&x| Counted(x)).collect();
+        CLONES.with(|clones| clones.set(0));
+        assert_eq!(
+            vec,
+            rbtree.into_cloned_iter().map(|x| x.0).collect::<Vec<_>>()
+        );
+        assert_eq!(vec.len(), CLONES.with(Cell::get));
+        assert_eq!(
+            None,
+            PersistentLazyRBTree::<Sum>::new().into_cloned_iter().next()
+        );
+    }
+
+    #[test]
+    fn merge_owned() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (_, left) = random_tree(rng.gen_range(0, 100), 100);
+            let (_, right) = random_tree(rng.gen_range(0, 100), 100);
+            let merged = PersistentLazyRBTree::merge(&left, &right);
+            let (left_dot, right_dot) = (left.to_dot(), right.to_dot());
+            let shared = PersistentLazyRBTree::merge_owned(left.clone(), right.clone());
+            assert_eq!(merged.to_dot(), shared.to_dot());
+            assert_eq!((left_dot, right_dot), (left.to_dot(), right.to_dot()));
+            assert_eq!(
+                merged.to_dot(),
+                PersistentLazyRBTree::merge_owned(left, right).to_dot()
+            );
+        }
+
+        let build = || -> (PersistentLazyRBTree<Sum>, PersistentLazyRBTree<Sum>) {
+            ((0..1000).map(Sum).collect(), (0..3).map(Sum).collect())
+        };
+        let (left, right) = build();
+        take_alloc_count();
+        let merged = PersistentLazyRBTree::merge(&left, &right);
+        let borrowed = take_alloc_count();
+        drop((left, right));
+        let (left, right) = build();
+        take_alloc_count();
+        let owned = PersistentLazyRBTree::merge_owned(left, right);
+        assert!(take_alloc_count() < borrowed);
+        assert_eq!(merged.to_dot(), owned.to_dot());
+    }
+
+    #[test]
+    fn set_range_iter() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (mut v, tree) = random_tree(rng.gen_range(0, 50), 100);
+            let start = rng.gen_range(0, v.len() + 1);
+            let items: Vec<i64> = (0..rng.gen_range(0, 60)).map(|x| -x).collect();
+            let tree = tree.set_range_iter(start, items.iter().cloned().map(Sum));
+            for (x, &y) in v[start..].iter_mut().zip(&items) {
+                *x = y;
+            }
+            assert_eq!(v, values(&tree));
+        }
+    }
+
+    #[test]
+    fn slice() {
+        let (v, tree) = random_tree(30, 100);
+        assert_eq!(v, values(&tree.slice(..)));
+        assert_eq!(v[..10], values(&tree.slice(..10)));
+        assert_eq!(v[..=10], values(&tree.slice(..=10)));
+        assert_eq!(v[10..], values(&tree.slice(10..)));
+        assert_eq!(v[10..20], values(&tree.slice(10..20)));
+        assert_eq!(v[10..=20], values(&tree.slice(10..=20)));
+        assert_eq!(v[30..], values(&tree.slice(30..)));
+        assert!(tree.slice(5..5).is_empty());
+    }
+
+    #[test]
+    fn try_split() {
+        let (vec, rbtree) = random_tree(10, 100);
+        assert_eq!(
+            Err(SeqError::OutOfBounds { index: 11, len: 10 }),
+            rbtree.try_split(11).map(|_| ())
+        );
+        for i in 0..=10 {
+            let (left, right) = rbtree.try_split(i).unwrap();
+            assert_eq!(
+                (vec[..i].to_vec(), vec[i..].to_vec()),
+                (values(&left), values(&right))
+            );
+        }
+    }
+
+    #[test]
+    fn common_prefix_len() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (va, a) = random_tree(rng.gen_range(0, 30), 3);
+            let (vb, b) = random_tree(rng.gen_range(0, 30), 3);
+            let expected = va.iter().zip(&vb).take_while(|(x, y)| x == y).count();
+            assert_eq!(expected, PersistentLazyRBTree::common_prefix_len(&a, &b));
+        }
+
+        let mut versions = vec![PersistentLazyRBTree::<Sum>::new()];
+        for i in 0..2000 {
+            let last = versions.last().unwrap();
+            versions.push(last.insert(last.len(), Sum(i)));
+        }
+        let (a, b) = (&versions[1500], &versions[2000]);
+        assert_eq!(1500, PersistentLazyRBTree::common_prefix_len(a, b));
+        let b = b.set(1000, Sum(-1));
+        assert_eq!(1000, PersistentLazyRBTree::common_prefix_len(a, &b));
+        assert_eq!(0, PersistentLazyRBTree::common_prefix_len(&versions[0], &b));
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Text(String);
+    impl Add for Text {
+        type Output = Self;
+        fn add(self, other: Self) -> Self {
+            Self(self.0 + &other.0)
+        }
+    }
+    impl Magma for Text {}
+    impl Semigroup for Text {}
+    impl Monoid for Text {
+        fn zero() -> Self {
+            Self(String::new())
+        }
+    }
+    impl LazyParam for Text {
+        type Element = Text;
+        type Mapping = Sum;
+        fn apply(_mapping: Self::Mapping, element: Self::Element) -> Self::Element {
+            element
+        }
+    }
+
+    #[test]
+    fn fold_keeps_order_of_non_commutative_monoid() {
+        let mut rng = rand::thread_rng();
+        let letters: Vec<_> = (b'a'..=b'z').map(|c| (c as char).to_string()).collect();
+        let tree: PersistentLazyRBTree<Text> = letters.iter().cloned().map(Text).collect();
+        let reversed: PersistentLazyRBTree<Text> = tree.rev().cloned().collect();
+        for _ in 0..100 {
+            let l = rng.gen_range(0, letters.len() + 1);
+            let r = rng.gen_range(l, letters.len() + 1);
+            assert_eq!(letters[l..r].concat(), tree.fold(l..r).0);
+            let mut expected = letters[letters.len() - r..letters.len() - l].to_vec();
+            expected.reverse();
+            assert_eq!(expected.concat(), reversed.fold(l..r).0);
+        }
+    }
+
+    #[test]
+    fn from_sorted_iter() {
+        let (mut vec, _) = random_tree(100, 10);
+        vec.sort();
+        let tree = PersistentLazyRBTree::<Sum>::from_sorted_iter(vec.iter().cloned().map(Sum));
+        let expected: PersistentLazyRBTree<Sum> = vec.iter().cloned().map(Sum).collect();
+        assert_eq!(expected.to_dot(), tree.to_dot());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn from_sorted_iter_unsorted() {
+        PersistentLazyRBTree::<Sum>::from_sorted_iter([Sum(1), Sum(3), Sum(2)]);
+    }
+
+    #[test]
+    fn for_each() {
+        let (vec, tree) = random_tree(500, 1000);
+        let mut visited = vec![];
+        tree.for_each(|x| visited.push(x.0));
+        assert_eq!(vec, visited);
+        PersistentLazyRBTree::<Sum>::new().for_each(|_| unreachable!());
+    }
+
+    #[test]
+    fn for_each_range() {
+        let mut rng = rand::thread_rng();
+        let (vec, tree) = random_tree(200, 1000);
+        for _ in 0..100 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let mut visited = vec![];
+            tree.for_each_range(l, r, |x| visited.push(x.0));
+            assert_eq!(vec[l..r], visited);
+        }
+    }
+
+    #[test]
+    fn edits_share_leaves() {
+        fn leaves(tree: &PersistentLazyRBTree<Sum>) -> Vec<*const Node<Sum>> {
+            let mut cursor = InOrder::new(tree.root.as_ref(), 0);
+            let mut leaves = vec![];
+            while let Some(node) = cursor.peek() {
+                match node.as_ref() {
+                    Leaf { .. } => {
+                        leaves.push(Rc::as_ptr(node));
+                        cursor.pop();
+                    }
+                    Tree { .. } => cursor.descend(),
+                }
+            }
+            leaves
+        }
+        let (_, tree) = random_tree(1000, 100);
+        let edited = tree.set(500, Sum(-1));
+        let (before, after) = (leaves(&tree), leaves(&edited));
+        let shared = before.iter().zip(&after).filter(|(x, y)| x == y).count();
+        assert_eq!(999, shared);
+        let edited = tree.insert(500, Sum(-1)).erase(0);
+        assert_eq!(
+            999,
+            leaves(&edited)
+                .iter()
+                .filter(|x| before.contains(x))
+                .count()
+        );
+    }
+
+    #[test]
+    fn assert_eq_vec() {
+        let (vec, tree) = random_tree(100, 100);
+        tree.assert_eq_vec(&vec.iter().cloned().map(Sum).collect::<Vec<_>>());
+        PersistentLazyRBTree::<Sum>::new().assert_eq_vec(&[]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "sequences differ at index 1: tree has Some(Sum(2)), slice has Some(Sum(3)) (lengths 3 and 3)"
+    )]
+    fn assert_eq_vec_mismatch() {
+        PersistentLazyRBTree::<Sum>::from_iter([Sum(1), Sum(2), Sum(3)]).assert_eq_vec(&[
+            Sum(1),
+            Sum(3),
+            Sum(3),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "sequences differ at index 2: tree has None, slice has Some(Sum(3))")]
+    fn assert_eq_vec_shorter() {
+        PersistentLazyRBTree::<Sum>::from_iter([Sum(1), Sum(2)]).assert_eq_vec(&[
+            Sum(1),
+            Sum(2),
+            Sum(3),
+        ]);
+    }
+
+    #[test]
+    fn rotate_range() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (mut vec, tree) = random_tree(rng.gen_range(1, 50), 100);
+            let l = rng.gen_range(0, vec.len());
+            let r = rng.gen_range(l + 1, vec.len() + 1);
+            let mid = rng.gen_range(l, r);
+            vec[l..r].rotate_left(mid - l);
+            assert_eq!(vec, values(&tree.rotate_range(l, r, mid)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "index 0 out of bounds for sequence of length 0")]
+    fn index_empty() {
+        let _ = &PersistentLazyRBTree::<Sum>::new()[0];
+    }
+
+    #[test]
+    #[should_panic(expected = "index 2 out of bounds for sequence of length 2")]
+    fn set_out_of_bounds() {
+        PersistentLazyRBTree::<Sum>::from_iter([Sum(1), Sum(2)]).set(2, Sum(0));
+    }
+
+    #[test]
+    fn check_invariants() {
+        let mut rng = rand::thread_rng();
+        assert!(PersistentLazyRBTree::<Sum>::new().check_invariants());
+        let (_, mut rbtree) = random_tree(300, 100);
+        for _ in 0..300 {
+            let i = rng.gen_range(0, rbtree.len() + 1);
+            let (left, right) = rbtree.split(i);
+            assert!(left.check_invariants() && right.check_invariants());
+            rbtree = PersistentLazyRBTree::merge(&right, &left).insert(i, Sum(0));
+            assert!(rbtree.check_invariants());
+        }
+    }
+
+    fn corrupted() -> PersistentLazyRBTree<Sum> {
+        let leaf = || Node::alloc(Leaf { val: Sum(0) });
+        let red = Node::alloc(Node::new(Red, leaf(), leaf()));
+        let red = Node::alloc(Node::new(Red, red, leaf()));
+        PersistentLazyRBTree::from_root(Node::alloc(Node::new(Black, red, leaf())))
+    }
+
+    #[test]
+    fn corrupted_tree_fails_check() {
+        assert!(!corrupted().check_invariants());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn merge_rejects_corrupted_tree() {
+        PersistentLazyRBTree::merge(&random_tree(10, 100).1, &corrupted());
+    }
+
+    #[test]
+    fn apply() {
+        let mut rng = rand::thread_rng();
+        let (mut vec, rbtree) = random_tree(50, 100);
+        let mut ops = vec![];
+        for _ in 0..300 {
+            let x = rng.gen_range(0, 100);
+            ops.push(match rng.gen_range(0, 3) {
+                0 => {
+                    let i = rng.gen_range(0, vec.len() + 1);
+                    vec.insert(i, x);
+                    Op::Insert(i, Sum(x))
+                }
+                1 if !vec.is_empty() => {
+                    let i = rng.gen_range(0, vec.len());
+                    vec.remove(i);
+                    Op::Erase(i)
+                }
+                _ if !vec.is_empty() => {
+                    let i = rng.gen_range(0, vec.len());
+                    vec[i] = x;
+                    Op::Set(i, Sum(x))
+                }
+                _ => {
+                    vec.push(x);
+                    Op::Insert(vec.len() - 1, Sum(x))
+                }
+            });
+        }
+        let before = values(&rbtree);
+        assert_eq!(vec, values(&rbtree.apply(&ops)));
+        assert_eq!(before, values(&rbtree));
+    }
+
+    #[test]
+    fn would_balance() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (_, a) = random_tree(rng.gen_range(0, 200), 100);
+            let (_, b) = random_tree(rng.gen_range(0, 200), 100);
+            let (rank_a, rank_b) = PersistentLazyRBTree::would_balance(&a, &b);
+            assert_eq!(
+                (
+                    a.root.as_ref().map_or(0, |root| root.rank()),
+                    b.root.as_ref().map_or(0, |root| root.rank())
+                ),
+                (rank_a, rank_b)
+            );
+            let merged = PersistentLazyRBTree::merge(&a, &b);
+            assert_eq!(merged.len(), PersistentLazyRBTree::merged_len(&a, &b));
+            let rank = merged.root_rank();
+            assert!(rank_a.max(rank_b) <= rank && rank <= rank_a.max(rank_b) + 1);
+        }
+    }
+
+    #[test]
+    fn prod_until() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(200, 100);
+        for _ in 0..200 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let threshold = rng.gen_range(1, 5000);
+            let mut acc = 0;
+            let mut expected = (vec.len(), None);
+            for (i, &x) in vec.iter().enumerate().skip(l) {
+                acc += x;
+                if acc >= threshold {
+                    expected = (i, Some(acc));
+                    break;
+                }
+            }
+            let expected = (expected.0, Sum(expected.1.unwrap_or(acc)));
+            assert_eq!(expected, rbtree.prod_until(l, |x| x.0 >= threshold));
+        }
+    }
+
+    #[test]
+    fn from_exact_iter() {
+        for n in (0..100).chain([1000, 1024, 1025]) {
+            let tree =
+                PersistentLazyRBTree::<Sum>::from_exact_iter((0..n as i32).map(|x| Sum(x.into())));
+            assert!(tree.iter().map(|x| x.0).eq(0..n));
+            assert!(tree.check_invariants());
+        }
+        let vec: Vec<_> = (0..1000).map(Sum).collect();
+        take_alloc_count();
+        let collected: PersistentLazyRBTree<Sum> = vec.iter().cloned().collect();
+        let collect_count = take_alloc_count();
+        let exact = PersistentLazyRBTree::<Sum>::from_exact_iter(vec.iter().cloned());
+        assert_eq!(2 * vec.len() - 1, take_alloc_count());
+        assert!(2 * vec.len() - 1 < collect_count);
+        assert_eq!(values(&collected), values(&exact));
+    }
+
+    #[test]
+    fn debug() {
+        let rbtree: PersistentLazyRBTree<Sum> = (0..3).map(Sum).collect();
+        assert_eq!("[Sum(0), Sum(1), Sum(2)]", format!("{:?}", rbtree));
+        assert_eq!("[]", format!("{:?}", PersistentLazyRBTree::<Sum>::new()));
+        assert_eq!(
+            "Some(Black { rank: 1, left: Sum(0), right: Red { rank: 1, left: Sum(1), right: Sum(2) } })",
+            format!("{:?}", rbtree.debug_structure())
+        );
+        assert_eq!(
+            "None",
+            format!("{:?}", PersistentLazyRBTree::<Sum>::new().debug_structure())
+        );
+    }
+
+    #[test]
+    fn count_less_in_range() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(300, 50);
+        for _ in 0..200 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let x = rng.gen_range(0, 60);
+            let expected = vec[l..r].iter().filter(|&&y| y < x).count();
+            assert_eq!(expected, rbtree.count_less_in_range(l, r, &Sum(x)));
+        }
+    }
+
+    #[test]
+    fn traversal_order_ignores_shape() {
+        let mut rng = rand::thread_rng();
+        let n = 300;
+        let expected: Vec<_> = (0..n).map(Sum).collect();
+        let mut trees = vec![
+            expected.iter().cloned().collect(),
+            PersistentLazyRBTree::from_exact_iter(expected.iter().cloned()),
+            (0..n)
+                .rev()
+                .fold(PersistentLazyRBTree::new(), |acc, x| acc.insert(0, Sum(x))),
+        ];
+        for _ in 0..20 {
+            let mut pieces: Vec<PersistentLazyRBTree<Sum>> = expected
+                .iter()
+                .map(|x| PersistentLazyRBTree::new().insert(0, x.clone()))
+                .collect();
+            while pieces.len() > 1 {
+                let i = rng.gen_range(0, pieces.len() - 1);
+                let right = pieces.remove(i + 1);
+                pieces[i] = PersistentLazyRBTree::merge(&pieces[i], &right);
+            }
+            let tree = pieces.pop().unwrap();
+            let i = rng.gen_range(0, n as usize + 1);
+            let (left, right) = tree.split(i);
+            trees.push(PersistentLazyRBTree::merge(&left, &right));
+        }
+        for tree in &trees {
+            assert_eq!(expected, tree.to_vec());
+            assert!(tree.iter().eq(&expected));
+            assert!(tree.rev().eq(expected.iter().rev()));
+            assert!(tree.into_cloned_iter().eq(expected.iter().cloned()));
+            let mut visited = vec![];
+            tree.for_each(|x| visited.push(x.clone()));
+            assert_eq!(expected, visited);
+        }
+    }
+
+    #[test]
+    fn scatter_insert() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (vec, rbtree) = random_tree(rng.gen_range(0, 50), 100);
+            let inserts: Vec<_> = (0..rng.gen_range(0, 20))
+                .map(|x| (rng.gen_range(0, vec.len() + 1), -x))
+                .collect();
+            let mut expected = vec.clone();
+            let mut sorted = inserts.clone();
+            sorted.sort_by_key(|&(i, _)| i);
+            for (k, &(i, x)) in sorted.iter().enumerate() {
+                expected.insert(i + k, x);
+            }
+            let inserts: Vec<_> = inserts.into_iter().map(|(i, x)| (i, Sum(x))).collect();
+            let inserted = rbtree.scatter_insert(&inserts);
+            assert_eq!(expected, values(&inserted));
+            assert!(inserted.check_invariants());
+        }
+    }
+
+    #[test]
+    fn is_rotation_of() {
+        let mut rng = rand::thread_rng();
+        let empty = PersistentLazyRBTree::<Sum>::new();
+        assert!(empty.is_rotation_of(&empty));
+        for _ in 0..200 {
+            let (vec, a) = random_tree(rng.gen_range(1, 20), 3);
+            let mut rotated = vec.clone();
+            rotated.rotate_left(rng.gen_range(0, vec.len()));
+            let b: PersistentLazyRBTree<Sum> = rotated.iter().cloned().map(Sum).collect();
+            assert!(a.is_rotation_of(&b));
+            let (other, c) = random_tree(vec.len(), 3);
+            let expected = (0..vec.len()).any(|k| {
+                let mut v = vec.clone();
+                v.rotate_left(k);
+                v == other
+            });
+            assert_eq!(expected, a.is_rotation_of(&c));
+            assert!(!a.is_rotation_of(&a.take(vec.len() - 1)));
+        }
+    }
+
+    // Random range updates mixed with point edits, checking every read
+    // against a `Vec`. Range updates go through `map_in_place` on a split-off
+    // window, so old versions must stay untouched while new ones change.
+    #[test]
+    fn range_update_harness() {
+        let mut rng = rand::thread_rng();
+        let (mut vec, mut rbtree) = random_tree(100, 100);
+        let mut history = vec![(vec.clone(), rbtree.clone())];
+        for _ in 0..2000 {
+            match rng.gen_range(0, 5) {
+                0 => {
+                    let l = rng.gen_range(0, vec.len() + 1);
+                    let r = rng.gen_range(l, vec.len() + 1);
+                    let (a, b) = (rng.gen_range(-1, 2), rng.gen_range(-10, 10));
+                    for x in &mut vec[l..r] {
+                        *x = a * *x + b;
+                    }
+                    let (ref left, ref right) = rbtree.split(r);
+                    let (ref left, mid) = left.split(l);
+                    let mid = &mid.map_in_place(|x| x.0 = a * x.0 + b);
+                    rbtree =
+                        PersistentLazyRBTree::merge(&PersistentLazyRBTree::merge(left, mid), right);
+                }
+                1 if !vec.is_empty() => {
+                    let i = rng.gen_range(0, vec.len());
+                    let x = rng.gen_range(0, 100);
+                    vec[i] = x;
+                    rbtree = rbtree.set(i, Sum(x));
+                }
+                2 => {
+                    let i = rng.gen_range(0, vec.len() + 1);
+                    let x = rng.gen_range(0, 100);
+                    vec.insert(i, x);
+                    rbtree = rbtree.insert(i, Sum(x));
+                }
+                3 if !vec.is_empty() => {
+                    let i = rng.gen_range(0, vec.len());
+                    vec.remove(i);
+                    rbtree = rbtree.erase(i);
+                }
+                _ => {
+                    let l = rng.gen_range(0, vec.len() + 1);
+                    let r = rng.gen_range(l, vec.len() + 1);
+                    assert_eq!(vec[l..r].iter().sum::<i64>(), rbtree.fold(l..r).0);
+                }
+            }
+            let i = rng.gen_range(0, vec.len() + 1);
+            assert_eq!(vec.get(i), rbtree.get(i).map(|x| &x.0));
+            if i < vec.len() {
+                assert_eq!(vec[i], rbtree[i].0);
+            }
+            history.push((vec.clone(), rbtree.clone()));
+        }
+        for (vec, rbtree) in history {
+            assert_eq!(vec, values(&rbtree));
+            assert_eq!(vec.iter().sum::<i64>(), rbtree.fold(0..vec.len()).0);
+        }
+    }
+
+    #[test]
+    fn window_iter() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(200, 1000);
+        for _ in 0..100 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            assert!(rbtree
+                .window_iter(l, r)
+                .map(|x| x.0)
+                .eq(vec[l..r].iter().cloned()));
+        }
+        assert_eq!(
+            None,
+            PersistentLazyRBTree::<Sum>::new().window_iter(0, 0).next()
+        );
+    }
+
+    #[test]
+    fn from_array() {
+        assert!(PersistentLazyRBTree::<Sum>::from([]).is_empty());
+        assert_eq!(vec![1], values(&PersistentLazyRBTree::from([Sum(1)])));
+        assert_eq!(
+            vec![1, 2, 3],
+            values(&PersistentLazyRBTree::from([Sum(1), Sum(2), Sum(3)]))
+        );
+        let tree = PersistentLazyRBTree::<Sum>::from([0; 100].map(Sum));
+        assert_eq!(vec![0; 100], values(&tree));
+        assert!(tree.check_invariants());
+    }
+
+    #[test]
+    #[should_panic(expected = "sequence length overflow")]
+    fn length_overflow() {
+        let leaf = Node::<Sum>::alloc(Leaf { val: Sum(0) });
+        let huge = Node::alloc(Tree {
+            val: Sum(0),
+            lazy: Sum(0),
+            color: Black,
+            rank: 1,
+            len: usize::MAX,
+            left: Rc::clone(&leaf),
+            right: Rc::clone(&leaf),
+            first: Sum(0),
+        });
+        Node::merge_black(&huge, &huge);
+    }
+
+    #[test]
+    fn decompose_range() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(500, 1000);
+        for _ in 0..200 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let pieces = rbtree.decompose_range(l, r);
+            assert!(pieces.len() <= 4 * rbtree.root_rank() + 2);
+            assert!(pieces.iter().all(|piece| piece.check_invariants()));
+            let folded: Vec<_> = pieces.iter().flat_map(values).collect();
+            assert_eq!(vec[l..r], folded);
+            let sum = pieces
+                .iter()
+                .fold(Sum(0), |acc, piece| acc + piece.fold(0..piece.len()));
+            assert_eq!(rbtree.fold(l..r), sum);
+        }
+    }
+
+    #[test]
+    fn hash_ignores_shape() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        fn hash(tree: &PersistentLazyRBTree<Sum>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            tree.hash(&mut hasher);
+            hasher.finish()
+        }
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(200, 100);
+        let mut trees = vec![rbtree.clone(), rbtree.canonicalize()];
+        for _ in 0..20 {
+            let i = rng.gen_range(0, vec.len() + 1);
+            let j = rng.gen_range(0, vec.len() + 1);
+            let (left, right) = rbtree.split(i);
+            let rotated = PersistentLazyRBTree::merge(&right, &left);
+            let (left, right) = rotated.split(j);
+            let rotated = PersistentLazyRBTree::merge(&right, &left);
+            trees.push(rotated.rotate_range(0, vec.len(), (2 * vec.len() - i - j) % vec.len()));
+        }
+        for tree in &trees {
+            assert_eq!(&rbtree, tree);
+            assert_eq!(vec, values(tree));
+            assert_eq!(hash(&rbtree), hash(tree));
+        }
+        assert_ne!(hash(&rbtree), hash(&rbtree.set(0, Sum(-1))));
+    }
+
+    #[test]
+    fn from_sorted_map_keys() {
+        let mut rng = rand::thread_rng();
+        let map: std::collections::BTreeMap<_, _> = (0..300)
+            .map(|i| (Sum(rng.gen_range(0, 10000)), i))
+            .collect();
+        let tree = PersistentLazyRBTree::<Sum>::from_sorted_map_keys(&map);
+        assert!(tree.check_invariants());
+        for (k, key) in map.keys().enumerate() {
+            assert_eq!(Some(key), tree.get(k));
+            assert_eq!(k, tree.partition_point(|x| x < key));
+        }
+    }
+
+    #[test]
+    fn any_all_in_range() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(300, 100);
+        for _ in 0..300 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            let x = rng.gen_range(0, 100);
+            let window = &vec[l..r];
+            assert_eq!(
+                window.iter().any(|&y| y < x),
+                rbtree.any_in_range(l, r, |y| y.0 < x)
+            );
+            assert_eq!(
+                window.iter().all(|&y| y < x),
+                rbtree.all_in_range(l, r, |y| y.0 < x)
+            );
+        }
+        assert!(rbtree.all_in_range(0, vec.len(), |y| y.0 < 100));
+        assert!(!rbtree.any_in_range(0, vec.len(), |y| y.0 >= 100));
+        assert!(rbtree.all_in_range(5, 5, |_| false));
+        let calls = Cell::new(0);
+        assert!(rbtree.any_in_range(0, vec.len(), |_| {
+            calls.set(calls.get() + 1);
+            true
+        }));
+        assert_eq!(1, calls.get());
+    }
+
+    #[test]
+    fn edit_script() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let (_, old) = random_tree(rng.gen_range(0, 100), 5);
+            let mut new = old.clone();
+            for _ in 0..rng.gen_range(0, 4) {
+                let l = rng.gen_range(0, new.len() + 1);
+                let r = rng.gen_range(l, (l + 5).min(new.len()) + 1);
+                let n = rng.gen_range(0, 6);
+                new = new.splice_iter(l, r, (0..n).map(|_| Sum(rng.gen_range(0, 5))));
+            }
+            let script = PersistentLazyRBTree::edit_script(&old, &new);
+            assert_eq!(values(&new), values(&old.apply(&script)));
+        }
+
+        let (_, old) = random_tree(1000, 100);
+        let new = old.set(500, Sum(-1));
+        assert_eq!(
+            vec![Op::Set(500, Sum(-1))],
+            PersistentLazyRBTree::edit_script(&old, &new)
+        );
+        let new = old.insert(300, Sum(-1)).insert(301, Sum(-2));
+        assert_eq!(
+            vec![Op::Insert(300, Sum(-1)), Op::Insert(301, Sum(-2))],
+            PersistentLazyRBTree::edit_script(&old, &new)
+        );
+        assert_eq!(
+            999,
+            PersistentLazyRBTree::common_suffix_len(&old, &old.erase(0))
+        );
+    }
+
+    #[test]
+    fn rebalance() {
+        let mut rng = rand::thread_rng();
+        let mut rbtree = PersistentLazyRBTree::<Sum>::new();
+        for i in 0..1000 {
+            rbtree = rbtree.insert(rng.gen_range(0, rbtree.len() + 1), Sum(i));
+            if i % 97 == 0 {
+                let rebalanced = rbtree.rebalance();
+                let optimal = (usize::BITS - rbtree.len().leading_zeros()) as usize;
+                assert!(rebalanced.height() + 1 >= optimal && rebalanced.height() <= optimal + 1);
+                assert!(rebalanced.height() <= rbtree.height());
+                assert!(rebalanced.check_invariants());
+                assert_eq!(values(&rbtree), values(&rebalanced));
+            }
+        }
+    }
+
+    #[test]
+    fn into_iter_ref() {
+        fn total<'a>(items: impl IntoIterator<Item = &'a Sum>) -> i64 {
+            items.into_iter().map(|x| x.0).sum()
+        }
+        let (vec, rbtree) = random_tree(100, 100);
+        let mut visited = vec![];
+        for x in &rbtree {
+            visited.push(x.0);
+        }
+        assert_eq!(vec, visited);
+        assert_eq!(vec.iter().sum::<i64>(), total(&rbtree));
+    }
+
+    #[test]
+    fn sample_weighted() {
+        let mut rng = rand::thread_rng();
+        let rbtree: PersistentLazyRBTree<Sum> = [1, 0, 3, 6].iter().cloned().map(Sum).collect();
+        let mut counts = [0; 4];
+        for _ in 0..10000 {
+            let x = rbtree.sample_weighted(&mut rng, |x| x.0 as u64).unwrap();
+            counts[[1, 0, 3, 6].iter().position(|&y| y == x.0).unwrap()] += 1;
+        }
+        assert_eq!(0, counts[1]);
+        for (i, &w) in [1, 0, 3, 6].iter().enumerate() {
+            assert!((counts[i] as f64 / 10000.0 - w as f64 / 10.0).abs() < 0.03);
+        }
+        let zeros: PersistentLazyRBTree<Sum> = [Sum(0), Sum(0)].into();
+        assert_eq!(None, zeros.sample_weighted(&mut rng, |x| x.0 as u64));
+        assert_eq!(
+            None,
+            PersistentLazyRBTree::<Sum>::new().sample_weighted(&mut rng, |x| x.0 as u64)
+        );
+    }
+
+    #[test]
+    fn starts_with() {
+        let (vec, rbtree) = random_tree(100, 100);
+        for n in 0..=100 {
+            assert!(rbtree.starts_with(&rbtree.take(n)));
+            let copy: PersistentLazyRBTree<Sum> = vec[..n].iter().cloned().map(Sum).collect();
+            assert!(rbtree.starts_with(&copy));
+        }
+        assert!(!rbtree.take(50).starts_with(&rbtree));
+        assert!(!rbtree.starts_with(&rbtree.take(50).set(49, Sum(-1))));
+        assert!(!rbtree.starts_with(&rbtree.skip(1).take(10)) || vec[1..11] == vec[..10]);
+        assert!(PersistentLazyRBTree::<Sum>::new().starts_with(&PersistentLazyRBTree::new()));
+    }
+
+    #[test]
+    fn iter_from_both_ends() {
+        let mut rng = rand::thread_rng();
+        for n in [0, 1, 2, 7, 300] {
+            let (vec, rbtree) = random_tree(n, 100);
+            let mut expected = vec.iter();
+            let mut iter = rbtree.iter();
+            loop {
+                assert_eq!(expected.len(), iter.len());
+                let (x, y) = if rng.gen_range(0, 2) == 0 {
+                    (expected.next(), iter.next())
+                } else {
+                    (expected.next_back(), iter.next_back())
+                };
+                assert_eq!(x.copied(), y.map(|y| y.0));
+                if x.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(None, iter.next());
+            assert_eq!(
+                vec,
+                rbtree.to_vec().into_iter().map(|x| x.0).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn range_rev() {
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(300, 100);
+        for _ in 0..300 {
+            let l = rng.gen_range(0, vec.len() + 1);
+            let r = rng.gen_range(l, vec.len() + 1);
+            assert!(rbtree
+                .range_rev(l, r)
+                .map(|x| x.0)
+                .eq(vec[l..r].iter().rev().copied()));
+        }
+        assert!(rbtree.range_rev(0, 300).eq(rbtree.iter().rev()));
+        assert_eq!(
+            None,
+            PersistentLazyRBTree::<Sum>::new().range_rev(0, 0).next()
+        );
+    }
+
+    #[test]
+    fn insert_into_empty_allocates_one_leaf() {
+        let empty = PersistentLazyRBTree::<Sum>::new();
+        let (rbtree, allocs) = count_allocations(|| empty.insert(0, Sum(1)));
+        assert_eq!(1, allocs);
+        assert_eq!(vec![1], values(&rbtree));
+        let root = rbtree.root.as_ref().unwrap();
+        assert_eq!(1, Rc::strong_count(root));
+        let (rbtree, allocs) = count_allocations(|| rbtree.erase(0));
+        assert_eq!(0, allocs);
+        assert!(rbtree.is_empty());
+    }
+
+    #[test]
+    fn seq_error() {
+        assert_eq!(
+            "index 3 out of bounds for sequence of length 2",
+            SeqError::OutOfBounds { index: 3, len: 2 }.to_string()
+        );
+        assert_eq!(
+            "range 4..1 invalid for sequence of length 5",
+            SeqError::InvalidRange { l: 4, r: 1, len: 5 }.to_string()
+        );
+        assert_eq!(
+            "sequence length overflow",
+            SeqError::LengthOverflow.to_string()
+        );
+
+        let leaf = Node::<Sum>::alloc(Leaf { val: Sum(0) });
+        let huge = PersistentLazyRBTree::from_root(Node::alloc(Tree {
+            val: Sum(0),
+            lazy: Sum(0),
+            color: Black,
+            rank: 1,
+            len: usize::MAX,
+            left: Rc::clone(&leaf),
+            right: Rc::clone(&leaf),
+            first: Sum(0),
+        }));
+        assert_eq!(
+            Err(SeqError::LengthOverflow),
+            PersistentLazyRBTree::try_merge(&huge, &huge).map(|_| ())
+        );
+        assert_eq!(
+            Err(SeqError::LengthOverflow),
+            huge.try_insert(0, Sum(1)).map(|_| ())
+        );
+        let (_, rbtree) = random_tree(3, 100);
+        assert_eq!(
+            Ok(6),
+            PersistentLazyRBTree::try_merge(&rbtree, &rbtree).map(|tree| tree.len())
+        );
+    }
+
+    #[test]
+    fn sort() {
+        let mut rng = rand::thread_rng();
+        let mut vec: Vec<_> = (0..500).map(|i| Keyed(rng.gen_range(0, 20), i)).collect();
+        let tree: PersistentLazyRBTree<Keyed> = vec.iter().copied().collect();
+        let sorted = tree.sort();
+        vec.sort();
+        let tags = |v: Vec<Keyed>| v.into_iter().map(|x| (x.0, x.1)).collect::<Vec<_>>();
+        assert_eq!(tags(vec), tags(sorted.to_vec()));
+        assert!(sorted.check_invariants());
+        assert_eq!(500, tree.len());
+        assert!(PersistentLazyRBTree::<Sum>::new().sort().is_empty());
+    }
+
+    #[test]
+    fn get_many() {
+        let (vec, rbtree) = random_tree(50, 100);
+        let [a, b, c, d] = rbtree.get_many([0, 49, 50, 49]);
+        assert_eq!(Some(vec[0]), a.map(|x| x.0));
+        assert_eq!(Some(vec[49]), b.map(|x| x.0));
+        assert_eq!(None, c);
+        assert_eq!(b, d);
+        assert_eq!([None], PersistentLazyRBTree::<Sum>::new().get_many([0]));
+        assert_eq!(0, rbtree.get_many([]).len());
+    }
+
+    #[test]
+    fn split_take() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (vec, rbtree) = random_tree(rng.gen_range(0, 100), 100);
+            let i = rng.gen_range(0, vec.len() + 1);
+            let (left, right) = rbtree.clone().split_take(i);
+            assert_eq!(
+                (vec[..i].to_vec(), vec[i..].to_vec()),
+                (values(&left), values(&right))
+            );
+            assert!(left.check_invariants() && right.check_invariants());
+            let (borrowed_left, borrowed_right) = rbtree.split(i);
+            assert_eq!(borrowed_left.to_dot(), left.to_dot());
+            assert_eq!(borrowed_right.to_dot(), right.to_dot());
+            drop(rbtree);
+            let joined = PersistentLazyRBTree::from_parts(left, right);
+            assert_eq!(vec, values(&joined));
+            let (left, right) = joined.split_take(i);
+            assert_eq!(vec, values(&PersistentLazyRBTree::from_parts(left, right)));
+        }
+
+        let rbtree: PersistentLazyRBTree<Sum> = (0..1000).map(Sum).collect();
+        take_alloc_count();
+        let borrowed = rbtree.split(500);
+        let borrowed_allocs = take_alloc_count();
+        drop(borrowed);
+        let owned = rbtree.split_take(500);
+        assert!(take_alloc_count() < borrowed_allocs);
+        assert_eq!(
+            vec![Sum(499), Sum(500)],
+            vec![owned.0[499].clone(), owned.1[0].clone()]
+        );
+    }
+
+    #[test]
+    fn range_shared() {
+        let block: PersistentLazyRBTree<Sum> = (0..10).map(Sum).collect();
+        let twice = PersistentLazyRBTree::merge(&block, &block);
+        let repeated = PersistentLazyRBTree::merge(&twice, &twice);
+        assert!(repeated.range_shared(0..10, 10..20));
+        assert!(repeated.range_shared(0..10, 30..40));
+        assert!(repeated.range_shared(0..20, 20..40));
+        assert!(repeated.range_shared(3..3, 7..7));
+        assert!(!repeated.range_shared(0..10, 0..20));
+        assert!(!repeated.range_shared(0..0, 0..1));
+        let (_, rbtree) = random_tree(40, 100);
+        assert!(!rbtree.range_shared(0..10, 10..20));
+    }
+
+    #[test]
+    fn total() {
+        assert_eq!(Sum(0), PersistentLazyRBTree::<Sum>::new().total());
+        let (vec, rbtree) = random_tree(100, 100);
+        assert_eq!(Sum(vec.iter().sum()), rbtree.total());
+        assert_eq!(rbtree.fold(0..rbtree.len()), rbtree.total());
+        // Far too long to walk, but the root already holds the answer.
+        let huge = (0..40).fold(PersistentLazyRBTree::<Sum>::from([Sum(1)]), |acc, _| {
+            PersistentLazyRBTree::merge(&acc, &acc)
+        });
+        assert_eq!(Sum(1 << 40), huge.total());
+    }
+
+    #[test]
+    fn range_eq_self() {
+        let block: PersistentLazyRBTree<Sum> = (0..7).map(Sum).collect();
+        let periodic: PersistentLazyRBTree<Sum> = (0..35).map(|i| Sum(i % 7)).collect();
+        assert!(periodic.range_eq_self(0..7, 7..14));
+        assert!(periodic.range_eq_self(0..28, 7..35));
+        assert!(periodic.range_eq_self(0..8, 7..15));
+        assert!(!periodic.range_eq_self(0..6, 1..7));
+        assert!(!periodic.range_eq_self(0..7, 7..13));
+        assert!(periodic.range_eq_self(3..3, 9..9));
+        let doubled = PersistentLazyRBTree::merge(&block, &block);
+        assert!(doubled.range_eq_self(0..7, 7..14));
+
+        let mut rng = rand::thread_rng();
+        let (vec, rbtree) = random_tree(60, 3);
+        for _ in 0..500 {
+            let len = rng.gen_range(0, 31);
+            let a = rng.gen_range(0, 61 - len);
+            let b = rng.gen_range(0, 61 - len);
+            assert_eq!(
+                vec[a..a + len] == vec[b..b + len],
+                rbtree.range_eq_self(a..a + len, b..b + len)
+            );
+        }
+    }
+
+    #[test]
+    fn gap_buffer() {
+        let (vec, rbtree) = random_tree(50, 100);
+        for cursor in [0, 1, 25, 49, 50] {
+            let (mut left, mut right_rev) = rbtree.to_gap_buffer(cursor);
+            assert_eq!(vec[..cursor], values(&left.iter().cloned().collect()));
+            assert!(right_rev
+                .iter()
+                .map(|x| x.0)
+                .eq(vec[cursor..].iter().rev().copied()));
+            let back = PersistentLazyRBTree::from_gap_buffer(left.clone(), right_rev.clone());
+            assert_eq!(vec, values(&back));
+            assert!(back.check_invariants());
+
+            left.push(Sum(-1));
+            right_rev.push(Sum(-2));
+            let mut expected = vec.clone();
+            expected.splice(cursor..cursor, [-1, -2]);
+            let edited = PersistentLazyRBTree::from_gap_buffer(left, right_rev);
+            assert_eq!(expected, values(&edited));
+        }
+        let (left, right_rev) = PersistentLazyRBTree::<Sum>::new().to_gap_buffer(0);
+        assert!(left.is_empty() && right_rev.is_empty());
+        assert!(PersistentLazyRBTree::<Sum>::from_gap_buffer(left, right_rev).is_empty());
+    }
+
+    #[test]
+    fn op_stats() {
+        let rbtree: PersistentLazyRBTree<Sum> = (0..1024).map(Sum).collect();
+        take_op_stats();
+        let inserted = rbtree.insert(500, Sum(-1));
+        let stats = take_op_stats();
+        assert_eq!((1, 2), (stats.splits, stats.merges));
+        assert!(0 < stats.split_nodes && stats.split_nodes <= 4 * 10);
+        assert!(0 < stats.merge_nodes && stats.merge_nodes <= 4 * 10);
+        assert_eq!(OpStats::default(), take_op_stats());
+        assert_eq!(Sum(-1), inserted[500]);
+
+        PersistentLazyRBTree::merge(&rbtree, &PersistentLazyRBTree::new());
+        rbtree.split(0);
+        assert_eq!(OpStats::default(), take_op_stats());
+        rbtree.clone().split_take(3);
+        PersistentLazyRBTree::merge_owned(rbtree.clone(), rbtree.clone());
+        let stats = take_op_stats();
+        assert_eq!((1, 1), (stats.splits, stats.merges));
+    }
+
+    #[test]
+    fn subrange_clamped() {
+        let (vec, rbtree) = random_tree(20, 100);
+        assert_eq!(vec[5..15], values(&rbtree.subrange_clamped(5, 15)));
+        assert_eq!(vec[5..], values(&rbtree.subrange_clamped(5, 100)));
+        assert_eq!(vec[5..15], values(&rbtree.subrange_clamped(15, 5)));
+        assert_eq!(vec[5..], values(&rbtree.subrange_clamped(100, 5)));
+        assert!(rbtree.subrange_clamped(30, 100).is_empty());
+        assert!(rbtree.subrange_clamped(7, 7).is_empty());
+        assert!(PersistentLazyRBTree::<Sum>::new()
+            .subrange_clamped(3, 1)
+            .is_empty());
+    }
+
+    #[test]
+    fn to_vec_is_linear() {
+        for n in [500, 1000, 2000, 4000] {
+            let (vec, rbtree) = random_tree(n, 100);
+            NODE_VISITS.with(|count| count.set(0));
+            let visited = rbtree.to_vec();
+            // Each of the n leaves and n - 1 internal nodes exactly once.
+            assert_eq!(2 * n - 1, NODE_VISITS.with(Cell::get));
+            assert!(visited.iter().map(|x| x.0).eq(vec));
+        }
+    }
+
+    #[test]
+    fn into_vec_reusing() {
+        let (vec, rbtree) = random_tree(100, 100);
+        let (shared, moved) = rbtree.clone().into_vec_reusing();
+        assert_eq!(
+            (vec.clone(), 0),
+            (shared.iter().map(|x| x.0).collect(), moved)
+        );
+        let (unique, moved) = rbtree.into_vec_reusing();
+        assert_eq!((vec, 100), (unique.iter().map(|x| x.0).collect(), moved));
+
+        let (vec, rbtree) = random_tree(50, 100);
+        let half = rbtree.take(25);
+        let (values, moved) = rbtree.into_vec_reusing();
+        assert_eq!(vec, values.iter().map(|x| x.0).collect::<Vec<_>>());
+        assert_eq!(25, moved);
+        drop(half);
+        assert_eq!(
+            (vec![], 0),
+            PersistentLazyRBTree::<Sum>::new().into_vec_reusing()
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let (vec, mut working) = random_tree(50, 100);
+        let snapshot = working.snapshot();
+        let history: [Snapshot<Sum>; 2] = [snapshot.clone(), working.skip(10).snapshot()];
+        for i in 0..50 {
+            working = working.set(i, Sum(-1)).insert(0, Sum(-2));
+        }
+        assert_eq!(vec, values(&snapshot));
+        assert_eq!(vec[10..], values(&history[1]));
+        assert_eq!(vec.len(), history[0].len());
+        let resumed = snapshot.into_inner().erase(0);
+        assert_eq!(vec[1..], values(&resumed));
+        assert_eq!(vec, values(&history[0]));
+    }
+
+    #[test]
+    fn prod_batch() {
+        let mut rng = rand::thread_rng();
+        let (_, rbtree) = random_tree(200, 100);
+        let ranges: Vec<(usize, usize)> = (0..300)
+            .map(|_| {
+                let l = rng.gen_range(0, 201);
+                (l, rng.gen_range(l, 201))
+            })
+            .collect();
+        NODE_VISITS.with(|count| count.set(0));
+        let expected: Vec<_> = ranges.iter().map(|&(l, r)| rbtree.fold(l..r)).collect();
+        let separate = NODE_VISITS.with(|count| count.replace(0));
+        take_alloc_count();
+        assert_eq!(expected, rbtree.prod_batch(&ranges));
+        assert_eq!(0, take_alloc_count());
+        // The batch walks each node at most once, however many queries share it.
+        let batched = NODE_VISITS.with(Cell::get);
+        assert!(batched < separate);
+        assert!(batched < 2 * 200);
+
+        let letters: PersistentLazyRBTree<Text> =
+            "abcdef".chars().map(|c| Text(c.to_string())).collect();
+        let folds: Vec<_> = letters
+            .prod_batch(&[(1, 4), (0, 6), (3, 3)])
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(vec!["bcd", "abcdef", ""], folds);
+        assert!(PersistentLazyRBTree::<Sum>::new()
+            .prod_batch(&[])
+            .is_empty());
+    }
+
+    #[test]
+    fn block_bounds() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            let (vec, rbtree) = random_tree(rng.gen_range(1, 60), rng.gen_range(1, 4));
+            let same = |x: &Sum, y: &Sum| x == y;
+            for index in 0..vec.len() {
+                let mut start = index;
+                while start > 0 && vec[start - 1] == vec[start] {
+                    start -= 1;
+                }
+                let mut end = index + 1;
+                while end < vec.len() && vec[end - 1] == vec[end] {
+                    end += 1;
+                }
+                assert_eq!((start, end), rbtree.block_bounds(index, same));
+            }
+        }
+        let rising: PersistentLazyRBTree<Sum> = [1, 2, 3, 1, 2, 0].map(Sum).into();
+        assert_eq!((3, 5), rising.block_bounds(4, |x, y| x < y));
+        assert_eq!((0, 3), rising.block_bounds(0, |x, y| x < y));
+        assert_eq!((5, 6), rising.block_bounds(5, |x, y| x < y));
+    }
+
+    #[test]
+    fn insert_tracked() {
+        let (mut vec, mut rbtree) = random_tree(20, 100);
+        for (i, x) in [(0, -1), (5, -2), (100, -3), (22, -4)] {
+            let (inserted, index) = rbtree.insert_tracked(i, Sum(x));
+            assert_eq!(i.min(rbtree.len()), index);
+            assert_eq!(Sum(x), inserted[index]);
+            vec.insert(index, x);
+            rbtree = inserted;
+        }
+        let (pushed, index) = rbtree.push_tracked(Sum(-5));
+        assert_eq!((rbtree.len(), Sum(-5)), (index, pushed[index].clone()));
+        vec.push(-5);
+        assert_eq!(vec, values(&pushed));
+        let (single, index) = PersistentLazyRBTree::<Sum>::new().push_tracked(Sum(7));
+        assert_eq!((0, vec![7]), (index, values(&single)));
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range out of bounds for sequence of length 3")]
+    fn slice_inclusive_end_at_max() {
+        PersistentLazyRBTree::<Sum>::from([1, 2, 3].map(Sum)).slice(..=usize::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range out of bounds for sequence of length 3")]
+    fn slice_excluded_start_at_max() {
+        PersistentLazyRBTree::<Sum>::from([1, 2, 3].map(Sum))
+            .slice((Bound::Excluded(usize::MAX), Bound::Unbounded));
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range out of bounds for sequence of length 3")]
+    fn slice_past_end() {
+        PersistentLazyRBTree::<Sum>::from([1, 2, 3].map(Sum)).slice(1..4);
+    }
 }
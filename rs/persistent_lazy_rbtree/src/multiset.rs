@@ -0,0 +1,106 @@
+use crate::{LazyParam, PersistentLazyRBTree};
+
+/// A persistent sorted multiset backed by a `PersistentLazyRBTree` kept in
+/// non-decreasing order. Every edit returns a new version.
+pub struct PersistentMultiset<T: LazyParam> {
+    tree: PersistentLazyRBTree<T>,
+}
+impl<T: LazyParam> PersistentMultiset<T>
+where
+    T::Element: Ord,
+{
+    fn from(tree: PersistentLazyRBTree<T>) -> Self {
+        Self { tree }
+    }
+    pub fn new() -> Self {
+        Self::from(PersistentLazyRBTree::new())
+    }
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+    /// Returns the number of elements less than `x`.
+    pub fn lower_bound(&self, x: &T::Element) -> usize {
+        self.tree.partition_point(|y| y < x)
+    }
+    /// Returns the number of elements not greater than `x`.
+    pub fn upper_bound(&self, x: &T::Element) -> usize {
+        self.tree.partition_point(|y| y <= x)
+    }
+    pub fn count(&self, x: &T::Element) -> usize {
+        self.upper_bound(x) - self.lower_bound(x)
+    }
+    /// Returns the `k`-th smallest element, counting from zero.
+    pub fn kth(&self, k: usize) -> Option<&T::Element> {
+        self.tree.get(k)
+    }
+    pub fn insert(&self, x: T::Element) -> Self {
+        Self::from(self.tree.insert_by(x, Ord::cmp))
+    }
+    /// Removes one occurrence of `x`, if any.
+    pub fn remove_one(&self, x: &T::Element) -> Self {
+        let index = self.lower_bound(x);
+        match self.tree.get(index) {
+            Some(y) if y == x => Self::from(self.tree.erase(index)),
+            _ => self.clone(),
+        }
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T::Element> {
+        self.tree.iter()
+    }
+}
+impl<T: LazyParam> Default for PersistentMultiset<T>
+where
+    T::Element: Ord,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T: LazyParam> Clone for PersistentMultiset<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::Sum;
+    use crate::PersistentMultiset;
+    use rand::Rng;
+
+    #[test]
+    fn equivalent_to_sorted_vec() {
+        let mut rng = rand::thread_rng();
+        let mut multiset = PersistentMultiset::<Sum>::new();
+        let mut vec = vec![];
+        let mut versions = vec![];
+        for _ in 0..2000 {
+            let x = rng.gen_range(0, 30);
+            if rng.gen_range(0, 3) == 0 {
+                multiset = multiset.remove_one(&Sum(x));
+                if let Some(i) = vec.iter().position(|&y| y == x) {
+                    vec.remove(i);
+                }
+            } else {
+                multiset = multiset.insert(Sum(x));
+                vec.insert(vec.partition_point(|&y| y <= x), x);
+            }
+            let x = rng.gen_range(0, 30);
+            assert_eq!(
+                vec.iter().filter(|&&y| y == x).count(),
+                multiset.count(&Sum(x))
+            );
+            let k = rng.gen_range(0, vec.len() + 1);
+            assert_eq!(vec.get(k), multiset.kth(k).map(|x| &x.0));
+            versions.push((multiset.clone(), vec.clone()));
+        }
+        for (multiset, vec) in versions {
+            assert!(multiset.iter().map(|x| x.0).eq(vec));
+        }
+    }
+}
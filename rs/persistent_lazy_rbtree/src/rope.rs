@@ -0,0 +1,236 @@
+use crate::{ChunkedLazyRBTree, LazyParam, Magma, Monoid, Semigroup, Unit};
+use std::fmt;
+use std::ops::{Add, Range};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TextInfo {
+    // The first byte folded in, which is the byte itself at a leaf.
+    first: Option<u8>,
+    newlines: usize,
+    // Number of bytes that start a UTF-8 encoded char.
+    chars: usize,
+}
+impl TextInfo {
+    fn new(byte: u8) -> Self {
+        Self {
+            first: Some(byte),
+            newlines: (byte == b'\n') as usize,
+            chars: is_char_start(byte) as usize,
+        }
+    }
+}
+fn is_char_start(byte: u8) -> bool {
+    byte & 0xC0 != 0x80
+}
+impl Add for TextInfo {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            first: self.first.or(other.first),
+            newlines: self.newlines + other.newlines,
+            chars: self.chars + other.chars,
+        }
+    }
+}
+impl Magma for TextInfo {}
+impl Semigroup for TextInfo {}
+impl Monoid for TextInfo {
+    fn zero() -> Self {
+        Self {
+            first: None,
+            newlines: 0,
+            chars: 0,
+        }
+    }
+}
+#[derive(Debug)]
+struct Text;
+impl LazyParam for Text {
+    type Element = TextInfo;
+    type Mapping = Unit;
+    fn apply(_: Self::Mapping, element: Self::Element) -> Self::Element {
+        element
+    }
+}
+
+/// A persistent byte string with newline-indexed line navigation.
+///
+/// Offsets are byte offsets; lines are separated by `b'\n'`, so an empty rope
+/// has a single empty line. Chars are counted by their UTF-8 leading bytes.
+#[derive(Clone, Default)]
+pub struct Rope {
+    // Bytes are small, so each leaf packs a run of them.
+    tree: ChunkedLazyRBTree<Text, 64>,
+}
+impl Rope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+    pub fn byte(&self, offset: usize) -> u8 {
+        self.tree[offset].first.unwrap()
+    }
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.tree.iter().map(|info| info.first.unwrap())
+    }
+    pub fn insert(&self, offset: usize, text: &str) -> Self {
+        let (ref left, ref right) = self.tree.split(offset);
+        let mid = &Self::from(text).tree;
+        Self {
+            tree: ChunkedLazyRBTree::merge(&ChunkedLazyRBTree::merge(left, mid), right),
+        }
+    }
+    pub fn remove(&self, range: Range<usize>) -> Self {
+        let (ref left, ref right) = self.tree.split(range.end);
+        let (ref left, _) = left.split(range.start);
+        Self {
+            tree: ChunkedLazyRBTree::merge(left, right),
+        }
+    }
+    pub fn line_count(&self) -> usize {
+        self.newlines_before(self.len()) + 1
+    }
+    /// Returns the offset of the first byte of `line`.
+    pub fn line_start(&self, line: usize) -> usize {
+        assert!(line < self.line_count());
+        if line == 0 {
+            0
+        } else {
+            self.tree.max_right(0, |info| info.newlines < line) + 1
+        }
+    }
+    /// Returns the zero-based line and byte column of `offset`.
+    pub fn offset_to_line_col(&self, offset: usize) -> (usize, usize) {
+        assert!(offset <= self.len());
+        let line = self.newlines_before(offset);
+        (line, offset - self.line_start(line))
+    }
+    pub fn char_count(&self) -> usize {
+        self.tree.fold(0..self.len()).chars
+    }
+    /// Returns the byte offset at which char `char_index` starts, or `len()`
+    /// for `char_index == char_count()`.
+    pub fn char_to_byte(&self, char_index: usize) -> usize {
+        assert!(char_index <= self.char_count());
+        self.tree.max_right(0, |info| info.chars <= char_index)
+    }
+    /// Returns the index of the char containing byte `offset`, or
+    /// `char_count()` for `offset == len()`. Offsets inside a multi-byte char
+    /// round down to that char.
+    pub fn byte_to_char(&self, offset: usize) -> usize {
+        assert!(offset <= self.len());
+        let chars = self.tree.fold(0..offset).chars;
+        if offset == self.len() || is_char_start(self.byte(offset)) {
+            chars
+        } else {
+            chars - 1
+        }
+    }
+    fn newlines_before(&self, offset: usize) -> usize {
+        self.tree.fold(0..offset).newlines
+    }
+}
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        Self {
+            tree: text.bytes().map(TextInfo::new).collect(),
+        }
+    }
+}
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.bytes().collect::<Vec<_>>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TextInfo;
+    use crate::{Monoid, Rope};
+    use rand::Rng;
+
+    #[test]
+    fn text_info_is_a_monoid() {
+        let mut rng = rand::thread_rng();
+        let mut random_info = || {
+            (0..rng.gen_range(0, 4))
+                .map(|_| TextInfo::new([b'a', b'\n', 0xC3, 0xA9][rng.gen_range(0, 4)]))
+                .fold(TextInfo::zero(), |acc, x| acc + x)
+        };
+        for _ in 0..100 {
+            let (a, b, c) = (random_info(), random_info(), random_info());
+            assert_eq!(a, a.clone() + TextInfo::zero());
+            assert_eq!(a, TextInfo::zero() + a.clone());
+            assert_eq!(
+                (a.clone() + b.clone()) + c.clone(),
+                a.clone() + (b.clone() + c.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn line_navigation() {
+        let mut rng = rand::thread_rng();
+        let mut string = String::new();
+        let mut rope = Rope::new();
+        for _ in 0..300 {
+            let offset = rng.gen_range(0, string.len() + 1);
+            let text: String = (0..rng.gen_range(0, 5))
+                .map(|_| ['a', 'b', '\n'][rng.gen_range(0, 3)])
+                .collect();
+            string.insert_str(offset, &text);
+            rope = rope.insert(offset, &text);
+            if rng.gen_range(0, 3) == 0 {
+                let l = rng.gen_range(0, string.len() + 1);
+                let r = rng.gen_range(l, string.len() + 1);
+                string.replace_range(l..r, "");
+                rope = rope.remove(l..r);
+            }
+
+            assert_eq!(string, rope.to_string());
+            let starts: Vec<usize> = std::iter::once(0)
+                .chain(string.match_indices('\n').map(|(i, _)| i + 1))
+                .collect();
+            assert_eq!(starts.len(), rope.line_count());
+            for (line, &start) in starts.iter().enumerate() {
+                assert_eq!(start, rope.line_start(line));
+            }
+            for offset in 0..=string.len() {
+                let line = starts.iter().rposition(|&start| start <= offset).unwrap();
+                assert_eq!(
+                    (line, offset - starts[line]),
+                    rope.offset_to_line_col(offset)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn char_indexing() {
+        let mut rng = rand::thread_rng();
+        let string: String = (0..500)
+            .map(|_| ['a', '\n', 'é', 'あ', '🦀'][rng.gen_range(0, 5)])
+            .collect();
+        let rope = Rope::from(string.as_str());
+        let starts: Vec<usize> = string.char_indices().map(|(i, _)| i).collect();
+        assert_eq!(starts.len(), rope.char_count());
+        for (char_index, &start) in starts.iter().enumerate() {
+            assert_eq!(start, rope.char_to_byte(char_index));
+        }
+        assert_eq!(string.len(), rope.char_to_byte(starts.len()));
+        for offset in 0..=string.len() {
+            let char_index = starts.iter().rposition(|&start| start <= offset).unwrap();
+            let char_index = if offset == string.len() {
+                starts.len()
+            } else {
+                char_index
+            };
+            assert_eq!(char_index, rope.byte_to_char(offset));
+        }
+    }
+}